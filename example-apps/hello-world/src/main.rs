@@ -1,6 +1,6 @@
 #![allow(unused)]
 use wlclient::{
-    client::{memory::SharedBuffer, WaylandClient},
+    client::{memory::PixelView, swapchain::Swapchain, WaylandClient},
     error::Result,
     protocol::{base::*, xdg_shell::*, WlEventMsg},
 };
@@ -13,20 +13,12 @@ struct Window {
     surface: WlSurface,
     wm_surface: XdgSurface,
     top_level: XdgTopLevel,
-    //buffer: Option<WlBuffer>,
-
-    canvas: WindowBuffer,
+    swapchain: Swapchain,
 }
 
-const MAX_WIDTH: i32 = 1920;
-const MAX_HEIGHT: i32 = 1080;
-const MAX_STRIDE: i32 = calc_stride(MAX_WIDTH);
-const MAX_WINDOW_SIZE: i32 = MAX_STRIDE * MAX_HEIGHT;
-
-#[inline(always)]
-const fn calc_stride(width: i32) -> i32 {
-    width * 4
-}
+// size of the window when the compositor lets us choose it
+const DEFAULT_WIDTH: i32 = 1920;
+const DEFAULT_HEIGHT: i32 = 1080;
 
 #[derive(Clone, Copy)]
 enum Color {
@@ -55,89 +47,32 @@ impl From<Color> for u32 {
     }
 }
 
-struct WindowBuffer {
-    width: u32,
-    height: u32,
-    buffer: SharedBuffer,
-}
-
-impl WindowBuffer {
-    fn new(width: i32, height: i32, buffer: SharedBuffer) -> Self {
-        let mut buffer = Self {
-            width: 0,
-            height: 0,
-            buffer,
-        };
-        buffer.resize(width, height);
-        buffer
-    }
-
-    fn resize(&mut self, width: i32, height: i32) {
-        assert!(width >= 0 && height >= 0);
-        assert!(
-            self.buffer.len() >= width as usize * height as usize * mem::size_of::<u32>(),
-            "Underline buffer overflow ( allocated-size: {}, new-size: {width} x {height} )",
-            self.buffer.len()
-        );
-
-        self.width = width as u32;
-        self.height = height as u32;
-    }
+// the pixels of a frame
+struct Canvas<'a>(PixelView<'a>);
 
+impl Canvas<'_> {
     fn fill(&mut self, color: Color) {
-        let color = u32::from(color);
-        let buffer = self.as_mut();
-        buffer.fill(color);
+        self.0.fill(color.into());
     }
 
     fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Color) {
-        let window_height = self.height;
-        let window_width = self.width;
-
-        let start_x = u32::min(window_width, x);
-        let end_x = u32::min(window_width, x + width);
-
-        let start_y = u32::min(window_height, y);
-        let end_y = u32::min(window_height, y + height);
-
-        let buffer = self.as_mut();
-        let color = u32::from(color);
-
-        for y in start_y..end_y {
-            for x in start_x..end_x {
-                let idx = y * window_width + x;
-                buffer[idx as usize] = color;
-            }
-        }
+        self.0.sub_view(x, y, width, height).fill(color.into());
     }
 
     fn set(&mut self, x: u32, y: u32, color: Color) {
         assert!(
-            x < self.width && y < self.height,
+            self.0.set(x, y, color.into()),
             "Invalid coordinates ({x}, {y}) for window with size {} x {}",
-            self.width,
-            self.height
+            self.0.width(),
+            self.0.height()
         );
-
-        let idx = y * self.width + x;
-        self.as_mut()[idx as usize] = u32::from(color);
     }
 
     fn get_height(&self) -> u32 {
-        self.height
+        self.0.height()
     }
     fn get_width(&self) -> u32 {
-        self.width
-    }
-
-    fn as_mut(&mut self) -> &mut [u32] {
-        let limit = self.width as usize * self.height as usize * mem::size_of::<u32>();
-        let buffer = &mut self.buffer.as_mut()[0..limit];
-
-        let (pref, buffer, suf) = unsafe { buffer.align_to_mut::<u32>() };
-        assert!(pref.is_empty() && suf.is_empty());
-
-        buffer
+        self.0.width()
     }
 }
 
@@ -164,23 +99,19 @@ fn create_window<T>(client: &mut WaylandClient<T>, app_id: &str, title: &str) ->
 
     surface.commit()?; // initial empty commit
 
-    let (pool, shared_buffer) = client.create_pool(MAX_WINDOW_SIZE)?;
-    client.upgrade_to_global(&pool)?;
-
-    let mut canvas = WindowBuffer::new(0, 0, shared_buffer);
-    canvas.fill(Color::White);
+    let swapchain = Swapchain::new(client, 0, 0, WlShmFormat::Xrgb8888, 2)?;
 
     Ok(Window {
         surface,
         top_level,
         wm_surface: xdg_surface,
-        canvas,
+        swapchain,
     })
 }
 
 #[allow(clippy::too_many_arguments)]
 fn render_char(
-    canvas: &mut WindowBuffer,
+    canvas: &mut Canvas,
     letter: char,
     line_width : u32,
     start_x: u32,
@@ -255,29 +186,20 @@ fn render_char(
     }
 }
 
-fn update(client: &mut WaylandClient<Window>, new_width: i32, new_height: i32) -> Result<()> {
-    //let window = client.get_custom_state().unwrap();
-
-    let pool: WlShmPool = client.get_global().expect("Failed to get pool");
-    let buffer: WlBuffer = client.new_object();
-    pool.create_buffer(
-        &buffer,
-        0,
-        new_width,
-        new_height,
-        calc_stride(new_width),
-        WlShmFormat::Xrgb8888,
-    )?;
-
-    client.add_event_handler(&buffer, |client, WlEventMsg { object_id, .. }| {
-        let buffer: WlBuffer = client.get_reference(object_id).unwrap();
-        buffer.destroy();
-    });
+fn update(
+    window: &mut Window,
+    client: &mut WaylandClient<Window>,
+    new_width: i32,
+    new_height: i32,
+) -> Result<()> {
+    window.swapchain.resize(client, new_width, new_height)?;
+    let Some(mut back) = window.swapchain.try_acquire(client)? else {
+        log::warn!("Every buffer is still used by the compositor, skipping the frame");
+        return Ok(());
+    };
 
-    let window = client.get_custom_state().unwrap();
     {
-        let canvas = &mut window.canvas;
-        canvas.resize(new_width, new_height);
+        let canvas = &mut Canvas(back.view()?);
         canvas.fill(Color::White);
 
         let square_height = 90;
@@ -318,32 +240,29 @@ fn update(client: &mut WaylandClient<Window>, new_width: i32, new_height: i32) -
 
     let surface = &window.surface;
     surface.damage_buffer(0, 0, i32::MAX, i32::MAX)?;
-    surface.attach(&buffer, 0, 0)?;
+    back.attach(surface, 0, 0)?;
     surface.commit()?;
-
-    window.canvas.resize(new_width, new_height);
     Ok(())
 }
 
 fn main() -> Result<()> {
     wlclient::init_log();
     let mut client = wlclient::connect::<Window>()?;
-    let window = create_window(&mut client, "hello-world-app", "Hello World")?;
+    let mut window = create_window(&mut client, "hello-world-app", "Hello World")?;
 
     // TODO: look which one comes first: xdg_surface @ ... <- configure( ... )
     // or xdg_top_level @ .. <- configure( ... )
-    client.add_event_handler(&window.top_level, |client, msg| match msg.event {
+    client.add_event_handler(&window.top_level, |window, client, msg| match msg.event {
         XdgTopLevelEvent::Close => {
             log::info!("Closing window");
             process::exit(0);
         }
 
         XdgTopLevelEvent::Configure { width, height, .. } => {
-            let window = client.get_custom_state().unwrap();
-            let buffer = &window.canvas;
+            let swapchain = &window.swapchain;
 
-            if buffer.get_width() != width as u32 || buffer.get_height() != height as u32 {
-                let Err(error) = update(client, width, height) else {
+            if width > 0 && height > 0 && (swapchain.width() != width || swapchain.height() != height) {
+                let Err(error) = update(window, client, width, height) else {
                     return;
                 };
                 log::error!("Updating application state: {error:?}")
@@ -352,21 +271,18 @@ fn main() -> Result<()> {
         _ => (),
     })?;
 
-    client.add_event_handler(&window.wm_surface, |client, msg| {
+    client.add_event_handler(&window.wm_surface, |window, client, msg| {
         let XdgSurfaceEvent::Configure { serial_nr } = msg.event;
         let xdg_surface: XdgSurface = client.get_reference(msg.object_id).unwrap();
         xdg_surface.ack_configure(serial_nr).unwrap();
 
-        let window = client.get_custom_state().unwrap();
-        let buffer = &window.canvas;
-        if buffer.get_width() == 0 && buffer.get_height() == 0 {
-            update(client, MAX_WIDTH, MAX_HEIGHT).unwrap();
+        let swapchain = &window.swapchain;
+        if swapchain.width() == 0 && swapchain.height() == 0 {
+            update(window, client, DEFAULT_WIDTH, DEFAULT_HEIGHT).unwrap();
         }
     });
 
-    client.set_custom_state(window);
     log::info!("State initialization completed...");
 
-    client.event_loop();
-    Ok(())
+    client.event_loop(&mut window)
 }
@@ -2,12 +2,13 @@
 use std::{mem, process};
 
 use wlclient::{
-    client::memory::SharedBuffer,
+    client::{damage::{DamageTracker, Rect}, memory::PixelView, swapchain::Swapchain},
     protocol::{
         self,
-        base::{WlBuffer, WlCompositor, WlShmFormat, WlShmPool, WlSurface},
+        base::{WlCompositor, WlShmFormat, WlSurface},
         xdg_shell::{XdgSurface, XdgSurfaceEvent, XdgTopLevel, XdgTopLevelEvent, XdgWmBase}, WlEventMsg,
     },
+    client::{dispatch::Dispatch, unhandled::UnhandledPolicy},
     WaylandClient,
 };
 
@@ -48,58 +49,41 @@ pub enum UIEvent {
 }
 
 pub struct Screen<'a> {
-    width: u32,
-    height: u32,
-    pixels: &'a mut [u32],
+    view: PixelView<'a>,
+    // what is drawn is damaged when the frame is committed
+    damage: &'a mut DamageTracker,
 }
 
 impl Screen <'_> {
 
     pub fn fill(&mut self, color: Color) {
-        let color = u32::from(color);
-        self.pixels.fill(color);
+        self.view.fill(color.into());
+        self.damage.add_all();
     }
 
     pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Color) {
-        let window_height = self.height;
-        let window_width = self.width;
-
-        let start_x = u32::min(window_width, x);
-        let end_x = u32::min(window_width, x + width);
-
-        let start_y = u32::min(window_height, y);
-        let end_y = u32::min(window_height, y + height);
-
-        let color = u32::from(color);
-
-        for y in start_y..end_y {
-            for x in start_x..end_x {
-                let idx = y * window_width + x;
-                self.pixels[idx as usize] = color;
-            }
-        }
+        self.view.sub_view(x, y, width, height).fill(color.into());
+        self.damage.add(Rect::new(x as i32, y as i32, width as i32, height as i32));
     }
 
     pub fn set(&mut self, x: u32, y: u32, color: Color) {
+        self.damage.add(Rect::new(x as i32, y as i32, 1, 1));
         assert!(
-            x < self.width && y < self.height,
+            self.view.set(x, y, color.into()),
             "Invalid coordinates ({x}, {y}) for window with size {} x {}",
-            self.width,
-            self.height
+            self.view.width(),
+            self.view.height()
         );
-
-        let idx = y * self.width + x;
-        self.pixels[idx as usize] = u32::from(color);
     }
 
     #[inline(always)]
     pub fn get_height(&self) -> u32 {
-        self.height
+        self.view.height()
     }
 
     #[inline(always)]
     pub fn get_width(&self) -> u32 {
-        self.width
+        self.view.width()
     }
 
 }
@@ -109,24 +93,23 @@ pub trait UIEventLoopHandler {
 }
 
 pub struct MiniUI {
-    client: Option<WaylandClient<Box<MiniUI>>>,
-    buffer: SharedBuffer,
-    handler: Box<dyn UIEventLoopHandler>,
-
-    current_width  : i32,
-    current_height : i32,
+    client: WaylandClient<UIState>,
+    state: UIState,
 }
 
-const MAX_WIDTH: i32 = 1920;
-const MAX_HEIGHT: i32 = 1080;
-const MAX_STRIDE: i32 = calc_stride(MAX_WIDTH);
-const MAX_WINDOW_SIZE: i32 = MAX_STRIDE * MAX_HEIGHT;
-
-#[inline(always)]
-const fn calc_stride(width: i32) -> i32 {
-    width * 4
+struct UIState {
+    surface: WlSurface,
+    xdg_surface: XdgSurface,
+    top_level: XdgTopLevel,
+    swapchain: Swapchain,
+    damage: DamageTracker,
+    handler: Box<dyn UIEventLoopHandler>,
 }
 
+// size of the window when the compositor lets us choose it
+const DEFAULT_WIDTH: i32 = 1920;
+const DEFAULT_HEIGHT: i32 = 1080;
+
 impl MiniUI {
     pub fn build<T>(app_title: &str, app_id: &str, handler: T) -> Result<Self>
     where
@@ -153,128 +136,121 @@ impl MiniUI {
             (surface, xdg_surface, top_level)
         };
         surface.commit()?; // initial empty commit
-        let (pool, mut buffer) = client.create_pool(MAX_WINDOW_SIZE)?;
-        buffer.fill(255); // fills the screen with white
-
-        client.upgrade_to_global(&pool)?;
-
-        // by now
-        client.upgrade_to_global(&surface)?;
-        client.upgrade_to_global(&xdg_surface)?;
-        client.upgrade_to_global(&top_level)?;
+        let swapchain = Swapchain::new(&mut client, 0, 0, WlShmFormat::Xrgb8888, 2)?;
 
         Ok(Self {
-            buffer,
-            client: Some(client),
-            handler: Box::new(handler),
-            current_height: 0,
-            current_width: 0
+            client,
+            state: UIState {
+                surface,
+                xdg_surface,
+                top_level,
+                swapchain,
+                damage: DamageTracker::default(),
+                handler: Box::new(handler),
+            },
         })
     }
 
-    pub fn event_loop(mut self) -> Result<()> {
-        let mut client = self.client.take().unwrap();
+    pub fn event_loop(self) -> Result<()> {
+        let Self { mut client, mut state } = self;
+
+        client.add_dispatch_handler(&state.top_level)?;
+        client.add_dispatch_handler(&state.xdg_surface)?;
+
+        // NOTE: Just to silence warnings, you want to do something else later
+        client.set_interface_unhandled_policy::<WlSurface>(UnhandledPolicy::Ignore);
 
-        let top_level : XdgTopLevel = client.get_global().unwrap();
-        let wm_surface : XdgSurface = client.get_global().unwrap();
-        let surface : WlSurface = client.get_global().unwrap();
+        client.event_loop(&mut state)
+    }
+}
 
-        client.add_event_handler(&top_level, |client, msg| match msg.event {
+impl Dispatch<XdgTopLevel> for UIState {
+    fn event(&mut self, client: &mut WaylandClient<Self>, _: &XdgTopLevel, event: XdgTopLevelEvent) {
+        match event {
             XdgTopLevelEvent::Close => {
-                let ui = client.get_custom_state().unwrap();
-                ui.dispatch(UIEvent::Exit);
+                if let Err(error) = self.exit(client) {
+                    log::error!("Closing application: {error:?}")
+                }
                 log::info!("Closing window");
                 process::exit(0);
             }
 
-            XdgTopLevelEvent::Configure { width, height, .. } => {
-
-                let ui = client.get_custom_state().unwrap();
-                if ui.current_width != width || ui.current_height != height  {
-                    let Err(error) = Self::update(client, width, height) else {
-                        return;
-                    };
+            XdgTopLevelEvent::Configure { width, height, .. }
+                if width > 0 && height > 0 && !self.has_size(width, height) =>
+            {
+                if let Err(error) = self.update(client, width, height) {
                     log::error!("Updating application state: {error:?}")
                 }
             }
             _ => (),
-        })?;
+        }
+    }
+}
 
-        // NOTE: Just to silence warnings, you want to do something else later
-        client.add_event_handler(&surface, |_,_| {})?;
-        client.add_event_handler(&wm_surface, |client, msg| {
-            let XdgSurfaceEvent::Configure { serial_nr } = msg.event;
-            let xdg_surface: XdgSurface = client.get_reference(msg.object_id).unwrap();
-            xdg_surface.ack_configure(serial_nr).unwrap();
-
-            let ui = client.get_custom_state().unwrap();
-            if ui.current_width == 0 || ui.current_height == 0 {
-                Self::update(client, MAX_WIDTH, MAX_HEIGHT).unwrap();
-            }
-        })?;
+impl Dispatch<XdgSurface> for UIState {
+    fn event(&mut self, client: &mut WaylandClient<Self>, xdg_surface: &XdgSurface, event: XdgSurfaceEvent) {
+        let XdgSurfaceEvent::Configure { serial_nr } = event;
+        xdg_surface.ack_configure(serial_nr).unwrap();
 
-        client.set_custom_state(Box::new(self));
-        client.event_loop();
-        Ok(())
+        if self.has_size(0, 0) {
+            self.update(client, DEFAULT_WIDTH, DEFAULT_HEIGHT).unwrap();
+        }
+    }
+}
+
+impl UIState {
+    fn has_size(&self, width: i32, height: i32) -> bool {
+        self.swapchain.width() == width && self.swapchain.height() == height
     }
 
-    fn dispatch(&mut self, event : UIEvent) {
-        assert!(self.current_height > 0 && self.current_width > 0);
+    // lets the handler draw the next frame
+    fn draw(&mut self, client : &mut WaylandClient<Self>, event : UIEvent) -> Result<()> {
+        assert!(!self.has_size(0, 0));
 
-        let screen = { 
+        let Some(mut back) = self.swapchain.try_acquire(client)? else {
+            log::warn!("Every buffer is still used by the compositor, skipping the frame");
+            return Ok(());
+        };
 
-            let limit = self.current_width as usize * self.current_height as usize * mem::size_of::<u32>();
-            let buffer = &mut self.buffer.as_mut()[0..limit];
+        let screen = Screen {
+            view: back.view()?,
+            damage: &mut self.damage,
+        };
+        self.handler.dispatch(screen, event);
 
-            // turn &[u8] iinto &[u32]
-            let (pref, pixels, suf) = unsafe { buffer.align_to_mut::<u32>() };
-            assert!(pref.is_empty() && suf.is_empty());
+        back.attach(&self.surface, 0, 0)?;
+        self.damage.commit(&self.surface, back.buffer().wl_buffer())
+    }
 
-            Screen {
-                width: self.current_width as u32,
-                height: self.current_height as u32,
-                pixels
-            }
+    // the handler is always told about the exit, but what it draws is never presented, so it
+    // gets an empty screen when every buffer is busy
+    fn exit(&mut self, client : &mut WaylandClient<Self>) -> Result<()> {
+        let mut back = if self.has_size(0, 0) { None } else { self.swapchain.try_acquire(client)? };
+        let view = match back.as_mut() {
+            Some(back) => back.view()?,
+            None => PixelView::new(&mut [], WlShmFormat::Xrgb8888, 0, 0, 0, 0)?,
         };
 
-        self.handler.dispatch(screen, event);
+        let screen = Screen { view, damage: &mut self.damage };
+        self.handler.dispatch(screen, UIEvent::Exit);
+        Ok(())
     }
 
-    fn update(client : &mut WaylandClient<Box<Self>>, new_width: i32, new_height: i32) -> Result<()> {
+    fn update(&mut self, client : &mut WaylandClient<Self>, new_width: i32, new_height: i32) -> Result<()> {
         assert!(new_height > 0 && new_width > 0);
 
-        let pool: WlShmPool = client.get_global().expect("Failed to get pool");
-        let buffer: WlBuffer = client.new_object();
-        pool.create_buffer(
-            &buffer,
-            0,
-            new_width,
-            new_height,
-            calc_stride(new_width),
-            WlShmFormat::Xrgb8888,
-        )?;
-
-        client.add_event_handler(&buffer, |client, WlEventMsg { object_id, .. }| {
-            let buffer: WlBuffer = client.get_reference(object_id).unwrap();
-            // TODO: when you start support frames and everything please reuse buffers
-            buffer.destroy(); 
-        });
-
-        let ui = client.get_custom_state().unwrap();
-        let event = if ui.current_width == 0 && ui.current_height == 0 {
+        let event = if self.has_size(0, 0) {
             UIEvent::Initialize
         } else {
             UIEvent::Resize
         };
 
-        ui.current_width  = new_width;
-        ui.current_height = new_height;
-        ui.dispatch(event);
-
-        let surface : WlSurface = client.get_global().unwrap();
-        surface.damage_buffer(0, 0, i32::MAX, i32::MAX)?;
-        surface.attach(&buffer, 0, 0)?;
-        surface.commit()?;
-        Ok(())
+        // the buffers are new, so is every one of their pixels
+        for buffer in self.swapchain.buffers() {
+            self.damage.forget_buffer(buffer.wl_buffer());
+        }
+        self.swapchain.resize(client, new_width, new_height)?;
+        self.damage.add_all();
+        self.draw(client, event)
     }
 }
@@ -1,80 +1,115 @@
-use std::process;
+use std::{process, thread, time::Duration};
 use wlclient::{
-    client::{memory::SharedBuffer, WaylandClient},
+    client::{dispatch::Dispatch, swapchain::Swapchain, WaylandClient},
     error::Result,
     protocol::{base::*, xdg_shell::*},
 };
 
-use log::info;
+use log::{info, warn};
 
 struct State {
     surface: WlSurface,
-    pixels: SharedBuffer,
-    buffer: WlBuffer,
+    swapchain: Swapchain,
     turn: u32,
     last_time: u32,
-    released: bool,
-    window_size: i32,
+    // the frame callbacks loop has started
+    started: bool,
 }
 
-fn update(client: &mut WaylandClient<State>, current_time: u32) {
-    let state = client.get_custom_state().unwrap();
-
-    if current_time == 0 || current_time - state.last_time >= 500 && state.released {
-        let window_size = state.window_size;
-        let turn = state.turn;
-        let data = state.pixels.as_mut();
-
-        for i in (0..window_size as usize).step_by(4) {
-            data[i] = 0;
-            data[i + 1] = 0;
-            data[i + 2] = 0;
-
-            data[i + turn as usize] = 255;
-        }
-
-        let surface = &state.surface;
-        let buffer = &state.buffer;
-
-        surface.damage_buffer(0, 0, i32::MAX, i32::MAX).unwrap();
-        surface.attach(buffer, 0, 0).unwrap();
-        surface.commit().unwrap();
-
-        state.last_time = current_time;
-        state.turn = (turn + 1) % 3;
-        state.released = false;
-    }
+// size of the window when the compositor lets us choose it
+const DEFAULT_WIDTH: i32 = 1920;
+const DEFAULT_HEIGHT: i32 = 1080;
 
+fn update(state: &mut State, client: &mut WaylandClient<State>, current_time: u32) {
     let cb: WlCallBack = client.new_object();
-    let state = client.get_custom_state().unwrap();
     state.surface.frame(&cb).unwrap();
 
     client
-        .add_event_handler(&cb, |client, msg| {
+        .add_event_handler(&cb, |state, client, msg| {
             let WlCallBackEvent::Done { data } = msg.event;
-            update(client, data);
+            update(state, client, data);
         })
         .unwrap();
+
+    if current_time == 0 || current_time - state.last_time >= 500 {
+        draw(state, client, current_time);
+    }
+
+    // the frame callback is only called after a commit
+    state.surface.commit().unwrap();
+}
+
+fn draw(state: &mut State, client: &mut WaylandClient<State>, current_time: u32) {
+    let Some(mut back) = state.swapchain.try_acquire(client).unwrap() else {
+        return;
+    };
+
+    // blue, green and then red
+    back.view().unwrap().fill(0xff << (8 * state.turn));
+
+    let surface = &state.surface;
+    surface.damage_buffer(0, 0, i32::MAX, i32::MAX).unwrap();
+    back.attach(surface, 0, 0).unwrap();
+
+    state.last_time = current_time;
+    state.turn = (state.turn + 1) % 3;
+}
+
+impl Dispatch<XdgTopLevel> for State {
+    fn event(&mut self, client: &mut WaylandClient<Self>, _: &XdgTopLevel, event: XdgTopLevelEvent) {
+        match event {
+            XdgTopLevelEvent::Close => {
+                info!("Closing window");
+                process::exit(0);
+            }
+
+            // the frame is drawn once the configure sequence ends (with the xdg_surface configure)
+            XdgTopLevelEvent::Configure { width, height, .. } if width > 0 && height > 0 => {
+                self.swapchain.resize(client, width, height).unwrap();
+            }
+            _ => (),
+        }
+    }
 }
 
+// the compositor might take a while to come back
+const RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
 fn main() -> Result<()> {
     wlclient::init_log();
 
     let mut client = wlclient::connect::<State>()?;
     info!("Initialization completed!");
+    let mut state = create_window(&mut client)?;
 
-    let width = 1920;
-    let height = 1080;
-    let stride = 4 * width; // size of a line
-    let window_size = width * height * 4;
+    // the objects of a lost connection are gone, so the window is created again, but the colors
+    // go on from where they were
+    loop {
+        let error = client.event_loop(&mut state).unwrap_err();
+        warn!("Connection lost ({error:?}), reconnecting");
+        reconnect(&mut client, &mut state)?;
 
-    let (pool, mut pixels) = client.create_pool(window_size)?;
-    info!("BufferPool created!");
+        let turn = state.turn;
+        state = create_window(&mut client)?;
+        state.turn = turn;
+    }
+}
 
-    pixels.as_mut().fill(255);
+fn reconnect(client: &mut WaylandClient<State>, state: &mut State) -> Result<()> {
+    let mut attempts = 1;
+    while let Err(error) = client.reconnect(state) {
+        if attempts == RECONNECT_ATTEMPTS {
+            return Err(error);
+        }
+        attempts += 1;
+        thread::sleep(RECONNECT_DELAY);
+    }
+    Ok(())
+}
 
-    let buffer: WlBuffer = client.new_object();
-    pool.create_buffer(&buffer, 0, width, height, stride, WlShmFormat::Xrgb8888)?;
+fn create_window(client: &mut WaylandClient<State>) -> Result<State> {
+    let swapchain = Swapchain::new(client, 0, 0, WlShmFormat::Xrgb8888, 2)?;
 
     let compositor: WlCompositor = client.get_global().expect("Failed to get WlCompositor");
 
@@ -93,72 +128,28 @@ fn main() -> Result<()> {
 
     surface.commit()?;
 
-    client.add_event_handler(&buffer, |client, _| {
-        client.get_custom_state().unwrap().released = true;
-    })?;
-
-    client.set_custom_state(State {
-        surface,
-        buffer,
-        pixels,
-        window_size: 0,
-        turn: 0,
-        last_time: 0,
-        released: false,
-    });
-
-    client.add_event_handler(&xdg_top_level, move |client, msg| match msg.event {
-        XdgTopLevelEvent::Close => {
-            info!("Closing window");
-            process::exit(0);
-        }
-
-        XdgTopLevelEvent::Configure { width, height, .. } => {
-            let state = client.get_custom_state().unwrap();
-            let new_window_size = 4 * height * width;
-
-            if new_window_size != state.window_size {
-                let old_window_size = state.window_size;
-                state.window_size = new_window_size;
-
-                let buffer: WlBuffer = client.new_object();
-                pool.create_buffer(&buffer, 0, width, height, 4 * width, WlShmFormat::Xrgb8888)
-                    .unwrap();
-
-                client
-                    .add_event_handler(&buffer, |client, _| {
-                        client.get_custom_state().unwrap().released = true;
-                    })
-                    .unwrap();
-
-                let state = client.get_custom_state().unwrap();
-                state.buffer.destroy().unwrap();
-                state.buffer = buffer;
-                state.released = true;
-                if old_window_size == 0 {
-                    update(client, 0);
-                }
-            }
-        }
-        _ => (),
-    })?;
+    client.add_dispatch_handler(&xdg_top_level)?;
 
-    client.add_event_handler(&xdg_surface, |client, msg| {
+    client.add_event_handler(&xdg_surface, |state, client, msg| {
         let XdgSurfaceEvent::Configure { serial_nr } = msg.event;
         let xdg_surface: XdgSurface = client.get_reference(msg.object_id).unwrap();
         xdg_surface.ack_configure(serial_nr).unwrap();
 
-        let state = client.get_custom_state().unwrap();
-        if state.window_size == 0 {
-            let surface = &state.surface;
-            let buffer  = &state.buffer;
-            surface.damage_buffer(0, 0, i32::MAX, i32::MAX).unwrap();
-            surface.attach(buffer, 0, 0).unwrap();
-            surface.commit().unwrap();
+        if state.swapchain.width() == 0 {
+            state.swapchain.resize(client, DEFAULT_WIDTH, DEFAULT_HEIGHT).unwrap();
         }
 
+        if !state.started {
+            state.started = true;
+            update(state, client, 0);
+        }
     })?;
 
-    client.event_loop();
-    Ok(())
+    Ok(State {
+        surface,
+        swapchain,
+        turn: 0,
+        last_time: 0,
+        started: false,
+    })
 }
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    error::Result,
+    protocol::{
+        base::{WlBuffer, WlSurface},
+        WaylandId, WlInterface,
+    },
+};
+
+/// A rectangle in buffer coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    /// Covers every buffer, whatever its size.
+    pub const MAX: Rect = Rect::new(0, 0, i32::MAX, i32::MAX);
+
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    pub fn right(&self) -> i32 {
+        self.x.saturating_add(self.width)
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.y.saturating_add(self.height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn area(&self) -> i64 {
+        if self.is_empty() {
+            return 0;
+        }
+        self.width as i64 * self.height as i64
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.right() <= self.right()
+            && other.bottom() <= self.bottom()
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.x < other.right()
+            && other.x < self.right()
+            && self.y < other.bottom()
+            && other.y < self.bottom()
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        if !self.intersects(other) {
+            return None;
+        }
+
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        Some(Rect::new(
+            x,
+            y,
+            self.right().min(other.right()).saturating_sub(x),
+            self.bottom().min(other.bottom()).saturating_sub(y),
+        ))
+    }
+
+    /// The smallest rectangle that contains both rectangles.
+    pub fn bounding_box(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()).saturating_sub(x),
+            self.bottom().max(other.bottom()).saturating_sub(y),
+        )
+    }
+
+    /// The parts of the rectangle outside of `other` (at most 4 rectangles, which don't
+    /// overlap).
+    pub fn subtract(&self, other: &Rect) -> Vec<Rect> {
+        let Some(hole) = self.intersection(other) else {
+            return if self.is_empty() { Vec::new() } else { vec![*self] };
+        };
+
+        // the bands above and below the hole take the whole width
+        let pieces = [
+            Rect::new(self.x, self.y, self.width, hole.y - self.y),
+            Rect::new(self.x, hole.bottom(), self.width, self.bottom() - hole.bottom()),
+            Rect::new(self.x, hole.y, hole.x - self.x, hole.height),
+            Rect::new(hole.right(), hole.y, self.right() - hole.right(), hole.height),
+        ];
+        pieces.into_iter().filter(|piece| !piece.is_empty()).collect()
+    }
+
+    // sharing a whole side, so that their bounding box is exactly their union
+    fn is_adjacent(&self, other: &Rect) -> bool {
+        let side_by_side = self.y == other.y
+            && self.height == other.height
+            && (self.right() == other.x || other.right() == self.x);
+        let stacked = self.x == other.x
+            && self.width == other.width
+            && (self.bottom() == other.y || other.bottom() == self.y);
+
+        side_by_side || stacked
+    }
+}
+
+/// Gathers the parts of a surface that were drawn during a frame, so that only those are
+/// damaged when the frame is committed.
+///
+/// It also remembers the damage of the last frames, so that a buffer that is reused (e.g. by a
+/// [`super::swapchain::Swapchain`]) can be brought up to date by redrawing only what changed
+/// since it was last presented, see [`DamageTracker::buffer_damage`].
+pub struct DamageTracker {
+    // of the current frame, merged
+    damage: Vec<Rect>,
+    max_rects: usize,
+    // damage of the last committed frames, the newest last
+    history: VecDeque<Vec<Rect>>,
+    frames: u64,
+    // number of frames committed when each buffer was last committed, by id, so the buffers
+    // have to be forgotten when they are destroyed since their id is reused
+    buffers: HashMap<WaylandId, u64>,
+}
+
+impl Default for DamageTracker {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_RECTS)
+    }
+}
+
+impl DamageTracker {
+    const DEFAULT_MAX_RECTS: usize = 16;
+    // enough for triple buffering
+    const HISTORY_SIZE: usize = 4;
+
+    /// A tracker that sends (at most) `max_rects` rectangles per frame.
+    pub fn new(max_rects: usize) -> Self {
+        assert!(max_rects > 0);
+
+        Self {
+            damage: Vec::new(),
+            max_rects,
+            history: VecDeque::with_capacity(Self::HISTORY_SIZE),
+            frames: 0,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// The damage of the current frame.
+    pub fn damage(&self) -> &[Rect] {
+        &self.damage
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        !self.damage.is_empty()
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if !rect.is_empty() {
+            Self::merge_into(&mut self.damage, rect, self.max_rects);
+        }
+    }
+
+    /// Damages the whole surface.
+    pub fn add_all(&mut self) {
+        self.add(Rect::MAX);
+    }
+
+    /// Forgets the damage history, e.g. when the buffers are reallocated.
+    pub fn reset(&mut self) {
+        self.damage.clear();
+        self.history.clear();
+        self.buffers.clear();
+    }
+
+    /// Forgets the frame `buffer` was last committed with, it must be called when the buffer is
+    /// destroyed since a new buffer can get its id.
+    pub fn forget_buffer(&mut self, buffer: &WlBuffer) {
+        self.buffers.remove(&buffer.get_object_id());
+    }
+
+    /// Damages the current frame on `surface` and commits it, `buffer` being the buffer that
+    /// was attached for the frame.
+    pub fn commit(&mut self, surface: &WlSurface, buffer: &WlBuffer) -> Result<()> {
+        for rect in &self.damage {
+            surface.damage_buffer(rect.x, rect.y, rect.width, rect.height)?;
+        }
+        surface.commit()?;
+
+        if self.history.len() == Self::HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(std::mem::take(&mut self.damage));
+        self.frames += 1;
+        self.buffers.insert(buffer.get_object_id(), self.frames);
+
+        Ok(())
+    }
+
+    /// What changed since `buffer` was last committed, i.e. what has to be redrawn before it
+    /// is presented again. `None` if the whole buffer has to be redrawn (it was never committed
+    /// or its frame is too old to be remembered).
+    pub fn buffer_damage(&self, buffer: &WlBuffer) -> Option<Vec<Rect>> {
+        let last_frame = *self.buffers.get(&buffer.get_object_id())?;
+        let missed = (self.frames - last_frame) as usize;
+        if missed > self.history.len() {
+            return None;
+        }
+
+        let mut damage = Vec::new();
+        for rect in self.history.iter().skip(self.history.len() - missed).flatten() {
+            Self::merge_into(&mut damage, *rect, self.max_rects);
+        }
+        Some(damage)
+    }
+
+    fn merge_into(damage: &mut Vec<Rect>, mut rect: Rect, max_rects: usize) {
+        // the merged rectangle might now overlap others
+        while let Some(index) = damage
+            .iter()
+            .position(|other| other.intersects(&rect) || other.is_adjacent(&rect))
+        {
+            rect = rect.bounding_box(&damage.swap_remove(index));
+        }
+        damage.push(rect);
+
+        while damage.len() > max_rects {
+            Self::merge_cheapest_pair(damage);
+        }
+    }
+
+    // merges the two rectangles whose bounding box adds the least area
+    fn merge_cheapest_pair(damage: &mut Vec<Rect>) {
+        let mut best = (0, 1, i64::MAX);
+        for i in 0..damage.len() {
+            for j in i + 1..damage.len() {
+                let (a, b) = (&damage[i], &damage[j]);
+                let waste = a.bounding_box(b).area() - a.area() - b.area();
+                if waste < best.2 {
+                    best = (i, j, waste);
+                }
+            }
+        }
+
+        let (i, j, _) = best;
+        let other = damage.swap_remove(j);
+        let merged = damage.swap_remove(i).bounding_box(&other);
+        Self::merge_into(damage, merged, usize::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::{
+        protocol::{ClientStream, StreamRef},
+        sync::Shared,
+    };
+
+    // the requests are written to a socket nobody reads
+    fn proxies(buffers: u32) -> (UnixStream, WlSurface, Vec<WlBuffer>) {
+        let (socket, server) = UnixStream::pair().unwrap();
+        let stream: StreamRef = Shared::new(ClientStream::new(socket));
+        let surface = WlSurface::build(3, stream.clone(), None);
+        let buffers = (0..buffers).map(|i| WlBuffer::build(10 + i, stream.clone(), None));
+        (server, surface, buffers.collect())
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_rects_are_merged() {
+        let mut tracker = DamageTracker::default();
+        tracker.add(Rect::new(0, 0, 10, 10));
+        tracker.add(Rect::new(5, 5, 10, 10));
+        assert_eq!(tracker.damage(), [Rect::new(0, 0, 15, 15)]);
+
+        tracker.add(Rect::new(20, 0, 10, 15));
+        tracker.add(Rect::new(50, 50, 10, 10));
+        assert_eq!(tracker.damage().len(), 3);
+
+        // sharing a side with the first one, then overlapping the last one once merged
+        tracker.add(Rect::new(15, 0, 5, 15));
+        tracker.add(Rect::new(25, 10, 30, 45));
+        tracker.add(Rect::new(0, 0, 0, 100));
+        assert_eq!(tracker.damage(), [Rect::new(0, 0, 60, 60)]);
+    }
+
+    #[test]
+    fn rects_are_merged_down_to_the_cap() {
+        let mut tracker = DamageTracker::new(2);
+        tracker.add(Rect::new(0, 0, 1, 1));
+        tracker.add(Rect::new(100, 100, 1, 1));
+        tracker.add(Rect::new(2, 0, 1, 1));
+
+        // the two closest ones waste the least area
+        let mut damage = tracker.damage().to_vec();
+        damage.sort_by_key(|rect| rect.x);
+        assert_eq!(damage, [Rect::new(0, 0, 3, 1), Rect::new(100, 100, 1, 1)]);
+
+        tracker.add(Rect::new(200, 200, 1, 1));
+        assert_eq!(tracker.damage().len(), 2);
+    }
+
+    #[test]
+    fn buffer_damage_covers_the_frames_since_the_buffer_was_committed() {
+        let (_server, surface, buffers) = proxies(2);
+        let (first, second) = (&buffers[0], &buffers[1]);
+        let mut tracker = DamageTracker::default();
+
+        tracker.add(Rect::new(0, 0, 10, 10));
+        tracker.commit(&surface, first).unwrap();
+        assert_eq!(tracker.buffer_damage(first), Some(Vec::new()));
+        assert_eq!(tracker.buffer_damage(second), None);
+
+        tracker.add(Rect::new(20, 20, 10, 10));
+        tracker.commit(&surface, second).unwrap();
+        assert_eq!(tracker.buffer_damage(first), Some(vec![Rect::new(20, 20, 10, 10)]));
+
+        tracker.add(Rect::new(0, 0, 5, 5));
+        tracker.commit(&surface, first).unwrap();
+        assert_eq!(tracker.buffer_damage(second), Some(vec![Rect::new(0, 0, 5, 5)]));
+
+        // the frames of the first buffer are forgotten once they are too old
+        for _ in 0..DamageTracker::HISTORY_SIZE {
+            tracker.add(Rect::new(40, 40, 1, 1));
+            tracker.commit(&surface, second).unwrap();
+        }
+        assert_eq!(tracker.buffer_damage(first), Some(vec![Rect::new(40, 40, 1, 1)]));
+        tracker.commit(&surface, second).unwrap();
+        assert_eq!(tracker.buffer_damage(first), None);
+        assert_eq!(tracker.buffer_damage(second), Some(Vec::new()));
+
+        tracker.forget_buffer(second);
+        assert_eq!(tracker.buffer_damage(second), None);
+    }
+}
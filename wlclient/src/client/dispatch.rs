@@ -0,0 +1,47 @@
+use super::{ListenerId, WaylandClient};
+use crate::{error::Result, protocol::WlInterface};
+
+/// Typed handling of the events of the interface `T` by the application state.
+///
+/// ```ignore
+/// impl Dispatch<XdgTopLevel> for AppState {
+///     fn event(&mut self, client: &mut WaylandClient<Self>, top_level: &XdgTopLevel, event: XdgTopLevelEvent) {
+///         // ...
+///     }
+/// }
+///
+/// client.add_dispatch_handler(&top_level)?;
+/// ```
+pub trait Dispatch<T: WlInterface>: Sized {
+    fn event(&mut self, client: &mut WaylandClient<Self>, proxy: &T, event: T::Event);
+}
+
+impl<S> WaylandClient<S> {
+    /// Appends a handler to the listeners chain of `object` that forwards its events to the
+    /// [`Dispatch`] implementation of the state.
+    pub fn add_dispatch_handler<T, E>(&mut self, object: &T) -> Result<ListenerId>
+    where
+        S: Dispatch<T>,
+        T: WlInterface<Event = E> + 'static,
+        E: 'static,
+    {
+        self.add_event_handler(object, |state, client, msg| {
+            let proxy: T = client.get_reference(msg.object_id).unwrap();
+            state.event(client, &proxy, msg.event);
+        })
+    }
+
+    /// Forwards the events of every object of `T` that weren't consumed by the listeners of the
+    /// object itself to the [`Dispatch`] implementation of the state.
+    pub fn set_default_dispatch<T, E>(&mut self)
+    where
+        S: Dispatch<T>,
+        T: WlInterface<Event = E> + 'static,
+        E: 'static,
+    {
+        self.set_default_handler::<T, E, _>(|state, client, msg| {
+            let proxy: T = client.get_reference(msg.object_id).unwrap();
+            state.event(client, &proxy, msg.event);
+        });
+    }
+}
@@ -0,0 +1,410 @@
+use std::{fmt, fs::File, io::Error as IoError, mem, os::fd::OwnedFd};
+
+use log::warn;
+use memmap::MmapOptions;
+
+use super::WaylandClient;
+use crate::{
+    error::Result,
+    protocol::{base::WlSurface, linux_dmabuf::*},
+    sync::MaybeSend,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    // not made of whole 16 bytes entries
+    InvalidFormatTable(u32),
+    MapFormatTable(IoError),
+    // a dev_t of another size
+    InvalidDevice(usize),
+    // the index points past the end of the format table
+    InvalidFormatIndex { index: u16, table_size: usize },
+    // some formats were sent before any format table
+    MissingFormatTable,
+    MissingMainDevice,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidFormatTable(size) => write!(f, "Format table of invalid size {size}"),
+            Self::MapFormatTable(error) => write!(f, "Error mapping the format table: {error}"),
+            Self::InvalidDevice(size) => write!(f, "Device id of invalid size {size}"),
+            Self::InvalidFormatIndex { index, table_size } => {
+                write!(f, "Format index {index} out of a table of {table_size} formats")
+            }
+            Self::MissingFormatTable => write!(f, "Formats sent before the format table"),
+            Self::MissingMainDevice => write!(f, "Feedback done without a main device"),
+        }
+    }
+}
+
+/// Modifier of the buffers whose layout is implicit (i.e. agreed on by the driver).
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// The formats the compositor supports for dmabuf buffers, from `zwp_linux_dmabuf_feedback_v1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmabufFeedback {
+    /// The device the compositor uses for compositing (a `dev_t`), the buffers should be
+    /// allocated on it unless a tranche says otherwise.
+    pub main_device: u64,
+    /// By order of preference.
+    pub tranches: Vec<DmabufTranche>,
+}
+
+/// A set of formats that is as efficient as any other of the same tranche.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DmabufTranche {
+    /// The device the buffers should be allocated on to be used with these formats.
+    pub target_device: u64,
+    /// See [`tranche_flags`].
+    pub flags: u32,
+    pub formats: Vec<DmabufFormat>,
+}
+
+/// A DRM fourcc format and the modifiers it supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmabufFormat {
+    pub format: u32,
+    pub modifiers: Vec<u64>,
+}
+
+impl DmabufFeedback {
+    /// Whether buffers of `format` with `modifier` can be used, in any tranche.
+    pub fn supports(&self, format: u32, modifier: u64) -> bool {
+        self.tranches
+            .iter()
+            .any(|tranche| tranche.modifiers(format).contains(&modifier))
+    }
+}
+
+impl DmabufTranche {
+    pub fn is_scanout(&self) -> bool {
+        self.flags & tranche_flags::SCANOUT != 0
+    }
+
+    /// The modifiers supported for `format` (empty if it isn't part of the tranche).
+    pub fn modifiers(&self, format: u32) -> &[u64] {
+        self.formats
+            .iter()
+            .find(|entry| entry.format == format)
+            .map_or(&[], |entry| &entry.modifiers)
+    }
+
+    fn add(&mut self, format: u32, modifier: u64) {
+        match self.formats.iter_mut().find(|entry| entry.format == format) {
+            Some(entry) if entry.modifiers.contains(&modifier) => (),
+            Some(entry) => entry.modifiers.push(modifier),
+            None => self.formats.push(DmabufFormat { format, modifiers: vec![modifier] }),
+        }
+    }
+}
+
+// Gathers the events of a feedback object until `done`. The format table and the main device
+// are kept between updates, since the compositor only sends them again when they change.
+#[derive(Default)]
+struct FeedbackBuilder {
+    // pairs of format and modifier
+    table: Option<Vec<(u32, u64)>>,
+    main_device: Option<u64>,
+    tranches: Vec<DmabufTranche>,
+    tranche: DmabufTranche,
+}
+
+impl FeedbackBuilder {
+    // size of an entry of the format table: the format, 4 bytes of padding and the modifier
+    const ENTRY_SIZE: usize = 16;
+
+    // returns the feedback once it is complete
+    fn handle(
+        &mut self,
+        event: ZwpLinuxDmabufFeedbackV1Event,
+    ) -> std::result::Result<Option<DmabufFeedback>, Error> {
+        use ZwpLinuxDmabufFeedbackV1Event as Event;
+
+        match event {
+            Event::FormatTable { file_descriptor, size } => {
+                self.table = Some(Self::read_table(file_descriptor, size)?);
+            }
+            Event::MainDevice { device } => self.main_device = Some(Self::device(&device)?),
+            Event::TrancheTargetDevice { device } => self.tranche.target_device = Self::device(&device)?,
+            Event::TrancheFlags { flags } => self.tranche.flags = flags,
+            Event::TrancheFormats { indices } => {
+                let table = self.table.as_ref().ok_or(Error::MissingFormatTable)?;
+                for index in indices.chunks_exact(2) {
+                    let index = u16::from_ne_bytes([index[0], index[1]]);
+                    let &(format, modifier) = table.get(index as usize).ok_or(
+                        Error::InvalidFormatIndex { index, table_size: table.len() },
+                    )?;
+                    self.tranche.add(format, modifier);
+                }
+            }
+            Event::TrancheDone => self.tranches.push(mem::take(&mut self.tranche)),
+            Event::Done => {
+                let main_device = self.main_device.ok_or(Error::MissingMainDevice)?;
+                return Ok(Some(DmabufFeedback {
+                    main_device,
+                    tranches: mem::take(&mut self.tranches),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn read_table(fd: OwnedFd, size: u32) -> std::result::Result<Vec<(u32, u64)>, Error> {
+        if !(size as usize).is_multiple_of(Self::ENTRY_SIZE) {
+            return Err(Error::InvalidFormatTable(size));
+        }
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        // the table is shared with every client, so it has to be mapped privately
+        let file = File::from(fd);
+        let table = unsafe {
+            MmapOptions::new()
+                .len(size as usize)
+                .map_copy(&file)
+                .map_err(Error::MapFormatTable)?
+        };
+
+        let entries = table
+            .chunks_exact(Self::ENTRY_SIZE)
+            .map(|entry| {
+                let format = u32::from_ne_bytes(entry[0..4].try_into().unwrap());
+                let modifier = u64::from_ne_bytes(entry[8..16].try_into().unwrap());
+                (format, modifier)
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn device(bytes: &[u8]) -> std::result::Result<u64, Error> {
+        let bytes: [u8; 8] = bytes.try_into().map_err(|_| Error::InvalidDevice(bytes.len()))?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Requests the dmabuf feedback of `surface` (or the default one if `None`), `handler` is
+    /// called with the whole feedback every time the compositor sends it, which it does again
+    /// whenever it changes (e.g. when the surface goes fullscreen and could be scanned out).
+    ///
+    /// Binds `zwp_linux_dmabuf_v1` if needed, the feedback requires its version 4. The feedback
+    /// stops being sent once the returned object is destroyed.
+    pub fn dmabuf_feedback<F>(
+        &mut self,
+        surface: Option<&WlSurface>,
+        mut handler: F,
+    ) -> Result<ZwpLinuxDmabufFeedbackV1>
+    where
+        F: FnMut(&mut S, &mut WaylandClient<S>, &DmabufFeedback) + MaybeSend + 'static,
+    {
+        let dmabuf: ZwpLinuxDmabufV1 = self.global_or_bind(4..)?;
+        let feedback: ZwpLinuxDmabufFeedbackV1 = self.new_object();
+        match surface {
+            Some(surface) => dmabuf.get_surface_feedback(&feedback, surface)?,
+            None => dmabuf.get_default_feedback(&feedback)?,
+        };
+
+        let mut builder = FeedbackBuilder::default();
+        self.add_event_handler(&feedback, move |state, client, msg| {
+            match builder.handle(msg.event) {
+                Ok(Some(feedback)) => handler(state, client, &feedback),
+                Ok(None) => (),
+                Err(error) => warn!("Invalid dmabuf feedback {}: {error}", msg.object_id),
+            }
+        })?;
+
+        Ok(feedback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        os::fd::{AsFd, FromRawFd, OwnedFd},
+    };
+
+    use super::{
+        super::test_server::{Arg, TestServer},
+        *,
+    };
+    use crate::protocol::WlInterface;
+    use ZwpLinuxDmabufFeedbackV1Event as Event;
+
+    const XRGB8888: u32 = 0x3432_5258;
+    const ARGB8888: u32 = 0x3432_5241;
+    const MAIN_DEVICE: u64 = 0xe280;
+    const SCANOUT_DEVICE: u64 = 0xe281;
+
+    // the table as the compositor would share it, and its size
+    fn table_file(entries: &[(u32, u64)]) -> (OwnedFd, u32) {
+        let fd = unsafe { libc::memfd_create(c"format-table".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0, "memfd_create failed");
+        let mut file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        for &(format, modifier) in entries {
+            file.write_all(&format.to_ne_bytes()).unwrap();
+            file.write_all(&[0; 4]).unwrap();
+            file.write_all(&modifier.to_ne_bytes()).unwrap();
+        }
+
+        let size = (entries.len() * FeedbackBuilder::ENTRY_SIZE) as u32;
+        (file.into(), size)
+    }
+
+    fn format_table(entries: &[(u32, u64)]) -> Event {
+        let (file_descriptor, size) = table_file(entries);
+        Event::FormatTable { file_descriptor, size }
+    }
+
+    fn indices(indices: &[u16]) -> Event {
+        Event::TrancheFormats { indices: indices.iter().flat_map(|i| i.to_ne_bytes()).collect() }
+    }
+
+    fn device(device: u64) -> Vec<u8> {
+        device.to_ne_bytes().to_vec()
+    }
+
+    fn feed(builder: &mut FeedbackBuilder, events: Vec<Event>) -> Option<DmabufFeedback> {
+        let mut feedback = None;
+        for event in events {
+            assert!(feedback.is_none(), "Events after done");
+            feedback = builder.handle(event).unwrap();
+        }
+        feedback
+    }
+
+    fn initial_events() -> Vec<Event> {
+        vec![
+            format_table(&[
+                (XRGB8888, DRM_FORMAT_MOD_LINEAR),
+                (XRGB8888, DRM_FORMAT_MOD_INVALID),
+                (ARGB8888, DRM_FORMAT_MOD_LINEAR),
+            ]),
+            Event::MainDevice { device: device(MAIN_DEVICE) },
+            Event::TrancheTargetDevice { device: device(SCANOUT_DEVICE) },
+            Event::TrancheFlags { flags: tranche_flags::SCANOUT },
+            indices(&[1]),
+            Event::TrancheDone,
+            Event::TrancheTargetDevice { device: device(MAIN_DEVICE) },
+            indices(&[0, 1]),
+            indices(&[2]),
+            Event::TrancheDone,
+            Event::Done,
+        ]
+    }
+
+    #[test]
+    fn feedback_is_built_from_the_table() {
+        let mut builder = FeedbackBuilder::default();
+        let feedback = feed(&mut builder, initial_events()).expect("The feedback is done");
+
+        assert_eq!(feedback.main_device, MAIN_DEVICE);
+        assert_eq!(feedback.tranches.len(), 2);
+
+        let scanout = &feedback.tranches[0];
+        assert!(scanout.is_scanout());
+        assert_eq!(scanout.target_device, SCANOUT_DEVICE);
+        assert_eq!(scanout.modifiers(XRGB8888), [DRM_FORMAT_MOD_INVALID]);
+        assert!(scanout.modifiers(ARGB8888).is_empty());
+
+        // the flags of the previous tranche aren't kept
+        let rendering = &feedback.tranches[1];
+        assert!(!rendering.is_scanout());
+        assert_eq!(rendering.target_device, MAIN_DEVICE);
+        assert_eq!(rendering.modifiers(XRGB8888), [DRM_FORMAT_MOD_LINEAR, DRM_FORMAT_MOD_INVALID]);
+        assert_eq!(rendering.modifiers(ARGB8888), [DRM_FORMAT_MOD_LINEAR]);
+
+        assert!(feedback.supports(ARGB8888, DRM_FORMAT_MOD_LINEAR));
+        assert!(!feedback.supports(ARGB8888, DRM_FORMAT_MOD_INVALID));
+    }
+
+    #[test]
+    fn updates_keep_the_table_and_the_main_device() {
+        let mut builder = FeedbackBuilder::default();
+        feed(&mut builder, initial_events()).expect("The feedback is done");
+
+        let update = vec![
+            Event::TrancheTargetDevice { device: device(MAIN_DEVICE) },
+            indices(&[2]),
+            Event::TrancheDone,
+            Event::Done,
+        ];
+        let feedback = feed(&mut builder, update).expect("The update is done");
+
+        assert_eq!(feedback.main_device, MAIN_DEVICE);
+        assert_eq!(feedback.tranches.len(), 1);
+        assert_eq!(feedback.tranches[0].formats, [DmabufFormat {
+            format: ARGB8888,
+            modifiers: vec![DRM_FORMAT_MOD_LINEAR],
+        }]);
+    }
+
+    #[test]
+    fn indices_past_the_table_are_rejected() {
+        let mut builder = FeedbackBuilder::default();
+        builder.handle(format_table(&[(XRGB8888, DRM_FORMAT_MOD_LINEAR)])).unwrap();
+
+        let error = builder.handle(indices(&[0, 1])).unwrap_err();
+        assert!(matches!(error, Error::InvalidFormatIndex { index: 1, table_size: 1 }));
+    }
+
+    #[test]
+    fn formats_need_a_table() {
+        let mut builder = FeedbackBuilder::default();
+        assert!(matches!(builder.handle(indices(&[0])), Err(Error::MissingFormatTable)));
+    }
+
+    #[test]
+    fn feedback_is_received_from_the_compositor() {
+        let mut dmabuf = 0;
+        let globals = [(ZwpLinuxDmabufV1::get_interface_name(), 4)];
+        let server = TestServer::spawn(&globals, move |connection, request| match request.opcode {
+            // the bind, the id of the new object is the last argument
+            0 if dmabuf == 0 => dmabuf = *request.args.last().unwrap(),
+            2 if request.object_id == dmabuf => {
+                let feedback = request.args[0];
+                let (table, size) = table_file(&[
+                    (XRGB8888, DRM_FORMAT_MOD_LINEAR),
+                    (XRGB8888, DRM_FORMAT_MOD_INVALID),
+                    (ARGB8888, DRM_FORMAT_MOD_LINEAR),
+                ]);
+                // 3 indices, so that the array is padded
+                let indices: Vec<u8> = [0u16, 1, 2].iter().flat_map(|i| i.to_ne_bytes()).collect();
+
+                connection.send(feedback, 1, &[Arg::Fd(table.as_fd()), Arg::Uint(size)]);
+                connection.send(feedback, 2, &[Arg::Bytes(&device(MAIN_DEVICE))]);
+                connection.send(feedback, 4, &[Arg::Bytes(&device(SCANOUT_DEVICE))]);
+                connection.send(feedback, 5, &[Arg::Bytes(&indices)]);
+                connection.send(feedback, 6, &[Arg::Uint(tranche_flags::SCANOUT)]);
+                connection.send(feedback, 3, &[]);
+                connection.send(feedback, 0, &[]);
+            }
+            _ => (),
+        });
+
+        let mut client = WaylandClient::<Option<DmabufFeedback>>::connect_to(server.path()).unwrap();
+        let feedback = client
+            .dmabuf_feedback(None, |received, _, feedback| *received = Some(feedback.clone()))
+            .unwrap();
+
+        let mut received = None;
+        client.roundtrip(&mut received).unwrap();
+        let received = received.expect("The feedback is done");
+
+        assert_eq!(received.main_device, MAIN_DEVICE);
+        assert_eq!(received.tranches.len(), 1);
+        let tranche = &received.tranches[0];
+        assert!(tranche.is_scanout());
+        assert_eq!(tranche.target_device, SCANOUT_DEVICE);
+        assert_eq!(tranche.modifiers(XRGB8888), [DRM_FORMAT_MOD_LINEAR, DRM_FORMAT_MOD_INVALID]);
+        assert_eq!(tranche.modifiers(ARGB8888), [DRM_FORMAT_MOD_LINEAR]);
+
+        drop((client, feedback));
+        server.join();
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    mem,
+};
+
+use crate::error::{Error, Result};
+
+// Listeners of events of type `E` (of the globals, the seats, or a single object), which are
+// notified without holding the lock of their owner so that they can add and remove listeners, or
+// cause another notification, while they are being called.
+//
+// A notification caused by a listener (e.g. during a roundtrip) is queued and notified to every
+// listener once the current one is over, since the listeners being called can't be called again.
+pub(super) type Listeners<F> = Vec<(u32, Box<F>)>;
+
+pub(super) struct ListenerList<F: ?Sized, E> {
+    listeners: Listeners<F>,
+    id_count: u32,
+    // listeners removed while they were being notified
+    removed: HashSet<u32>,
+    // whether every listener was removed while they were being notified
+    cleared: bool,
+    // events waiting for the notification in progress (if any) to be over
+    pending: VecDeque<E>,
+    notifying: bool,
+}
+
+impl<F: ?Sized, E> ListenerList<F, E> {
+    pub(super) fn new() -> Self {
+        Self {
+            listeners: Vec::new(),
+            id_count: 0,
+            removed: HashSet::new(),
+            cleared: false,
+            pending: VecDeque::new(),
+            notifying: false,
+        }
+    }
+
+    pub(super) fn add(&mut self, listener: Box<F>) -> u32 {
+        self.id_count += 1;
+        self.listeners.push((self.id_count, listener));
+        self.id_count
+    }
+
+    pub(super) fn remove(&mut self, id: u32) -> Result<()> {
+        if let Some(idx) = self.listeners.iter().position(|(other, _)| *other == id) {
+            self.listeners.remove(idx);
+            return Ok(());
+        }
+
+        // it might be one of the listeners being notified
+        if self.notifying && id <= self.id_count && self.removed.insert(id) {
+            return Ok(());
+        }
+        Err(Error::NoSuchListener)
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.listeners.clear();
+        self.cleared = self.notifying;
+    }
+
+    pub(super) fn is_notifying(&self) -> bool {
+        self.notifying
+    }
+
+    // whether there are no listeners, assuming there are while some are being notified
+    pub(super) fn is_empty(&self) -> bool {
+        self.listeners.is_empty() && !self.notifying
+    }
+
+    // Queues `event`, returns whether the caller has to notify it (with `next_event`), i.e.
+    // whether there wasn't a notification in progress already.
+    pub(super) fn queue(&mut self, event: E) -> bool {
+        self.pending.push_back(event);
+        !mem::replace(&mut self.notifying, true)
+    }
+
+    // The next queued event and the listeners to notify, which have to be given back with
+    // `restore`. `None` once every event was notified.
+    pub(super) fn next_event(&mut self) -> Option<(E, Listeners<F>)> {
+        match self.pending.pop_front() {
+            Some(event) => Some((event, mem::take(&mut self.listeners))),
+            None => {
+                self.notifying = false;
+                None
+            }
+        }
+    }
+
+    pub(super) fn restore(&mut self, mut listeners: Listeners<F>) {
+        if mem::take(&mut self.cleared) {
+            listeners.clear();
+        }
+
+        // keep the listeners added in the meantime at the end
+        listeners.retain(|(id, _)| !self.removed.contains(id));
+        listeners.append(&mut self.listeners);
+        self.listeners = listeners;
+        self.removed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns the event to notify from within the listener, if any
+    type List = ListenerList<dyn FnMut(u32) -> Option<u32>, u32>;
+
+    fn ids(list: &List) -> Vec<u32> {
+        list.listeners.iter().map(|(id, _)| *id).collect()
+    }
+
+    // what a client does without its lock, `during` is called along with each listener
+    fn notify(
+        list: &mut List,
+        event: u32,
+        notified: &mut Vec<(u32, u32)>,
+        during: &mut dyn FnMut(&mut List, u32),
+    ) {
+        if !list.queue(event) {
+            return;
+        }
+
+        while let Some((event, mut listeners)) = list.next_event() {
+            for (id, listener) in listeners.iter_mut() {
+                notified.push((*id, event));
+                during(list, event);
+                if let Some(nested) = listener(event) {
+                    notify(list, nested, notified, during);
+                }
+            }
+            list.restore(listeners);
+        }
+    }
+
+    #[test]
+    fn nested_notifications_reach_every_listener() {
+        let mut list = List::new();
+        let first = list.add(Box::new(|event| (event == 1).then_some(2)));
+        let second = list.add(Box::new(|_| None));
+
+        let mut notified = Vec::new();
+        notify(&mut list, 1, &mut notified, &mut |_, _| ());
+        assert_eq!(notified, [(first, 1), (second, 1), (first, 2), (second, 2)]);
+        assert!(!list.notifying);
+    }
+
+    #[test]
+    fn changes_during_notifications_are_kept() {
+        let mut list = List::new();
+        let first = list.add(Box::new(|event| (event == 1).then_some(2)));
+        let second = list.add(Box::new(|_| None));
+        let third = second + 1;
+
+        let mut notified = Vec::new();
+        notify(&mut list, 1, &mut notified, &mut |list, event| {
+            if event == 1 && list.id_count < third {
+                list.add(Box::new(|_| None));
+                list.remove(second).unwrap();
+            }
+        });
+
+        // a removed listener is still notified of the current event, an added one isn't
+        assert_eq!(notified, [(first, 1), (second, 1), (first, 2), (third, 2)]);
+        assert_eq!(ids(&list), [first, third]);
+    }
+
+    #[test]
+    fn listeners_cleared_during_notifications_are_dropped() {
+        let mut list = List::new();
+        list.add(Box::new(|event| (event == 1).then_some(2)));
+        let added = list.id_count + 1;
+
+        let mut notified = Vec::new();
+        notify(&mut list, 1, &mut notified, &mut |list, event| {
+            if event == 1 && list.id_count < added {
+                list.clear();
+                list.add(Box::new(|_| None));
+            }
+        });
+
+        assert_eq!(notified, [(1, 1), (added, 2)]);
+        assert_eq!(ids(&list), [added]);
+    }
+
+    #[test]
+    fn unknown_listeners_cannot_be_removed() {
+        let mut list = List::new();
+        let id = list.add(Box::new(|_| None));
+        assert!(list.remove(id + 1).is_err());
+
+        list.remove(id).unwrap();
+        assert!(list.remove(id).is_err());
+    }
+}
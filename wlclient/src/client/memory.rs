@@ -1,47 +1,365 @@
+use errno::{errno, Errno};
+use log::debug;
 use memmap::{MmapMut, MmapOptions};
 use std::{
-    ffi::CString, fs::File, ops::{Deref, DerefMut}, os::fd::FromRawFd
+    ffi::CString,
+    fmt,
+    fs::File,
+    io::Error as IoError,
+    ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, FromRawFd},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::{
-    Result, error::fallback_error
-};
+use crate::protocol::base::WlShmFormat;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // every name tried with shm_open was already taken
+    NameCollision,
+    // bigger than what the protocol can describe
+    TooLarge(usize),
+    ShmOpen(Errno),
+    ShmUnlink(Errno),
+    Seal(Errno),
+    Resize(IoError),
+    Map(IoError),
+    // pixels that aren't made of whole bytes, or formats with several planes
+    UnsupportedFormat(WlShmFormat),
+    // the pixels don't fit in the memory
+    OutOfBounds { required: usize, available: usize },
+    // empty, or with lines too short for their pixels
+    InvalidBufferSize { width: i32, height: i32, stride: i32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NameCollision => write!(f, "No free name for the shared memory file"),
+            Self::TooLarge(size) => write!(f, "{size} bytes don't fit in a wl_shm_pool"),
+            Self::ShmOpen(errno) => write!(f, "Error creating with shm_open: {errno}"),
+            Self::ShmUnlink(errno) => write!(f, "Error unlinking the shared memory file: {errno}"),
+            Self::Seal(errno) => write!(f, "Error sealing the shared memory file: {errno}"),
+            Self::Resize(error) => write!(f, "Error resizing the shared memory file: {error}"),
+            Self::Map(error) => write!(f, "Error mapping the shared memory file: {error}"),
+            Self::UnsupportedFormat(format) => write!(f, "No pixel view for {format:?} buffers"),
+            Self::OutOfBounds { required, available } => {
+                write!(f, "The pixels need {required} bytes but only {available} are available")
+            }
+            Self::InvalidBufferSize { width, height, stride } => {
+                write!(f, "Invalid {width}x{height} buffer with lines of {stride} bytes")
+            }
+        }
+    }
+}
 
 pub struct SharedBuffer (pub MmapMut);
 
 impl SharedBuffer {
+    const SHM_OPEN_RETRIES: usize = 100;
 
+    /// Allocates `size` bytes of memory that can be shared with the compositor.
+    ///
+    /// The file is created with `memfd_create` and sealed against shrinking (so the compositor
+    /// can't be made to fault on a truncated pool), with a fallback on an unlinked `shm_open`
+    /// file when memfds aren't available.
     pub fn alloc(size: usize) -> Result<(Self, File)> {
-        let filename = CString::new("my-own-custom-file")?;
+        let shm_file = match Self::memfd_create() {
+            Some(file) => {
+                file.set_len(size as u64).map_err(Error::Resize)?;
+                Self::seal(&file)?;
+                file
+            }
+            None => {
+                let file = Self::shm_open()?;
+                file.set_len(size as u64).map_err(Error::Resize)?;
+                file
+            }
+        };
+
+        let data = unsafe { MmapOptions::new().map_mut(&shm_file).map_err(Error::Map)? };
+
+        Ok((Self(data), shm_file))
+    }
+
+    fn memfd_create() -> Option<File> {
         let fd = unsafe {
-            libc::shm_open(
-                filename.as_ptr(),
-                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
-                0o666,
-            )
+            libc::memfd_create(c"wlclient-shm".as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
         };
 
         if fd < 0 {
-            return Err(fallback_error!(
-                "Error creating with shm_open '{}'",
-                errno::errno()
-            ));
+            debug!("memfd_create failed ({}), falling back to shm_open", errno());
+            return None;
         }
 
-        let res = unsafe { libc::shm_unlink(filename.as_ptr()) };
+        Some(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn seal(file: &File) -> Result<()> {
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL;
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) };
         if res < 0 {
-            return Err(fallback_error!("Error unlinking '{}'", errno::errno()));
+            return Err(Error::Seal(errno()));
         }
+        Ok(())
+    }
 
-        let shm_file = unsafe { File::from_raw_fd(fd) };
+    fn shm_open() -> Result<File> {
+        for _ in 0..Self::SHM_OPEN_RETRIES {
+            let filename = Self::random_name();
+            let fd = unsafe {
+                libc::shm_open(
+                    filename.as_ptr(),
+                    libc::O_CREAT | libc::O_EXCL | libc::O_RDWR | libc::O_CLOEXEC,
+                    0o600,
+                )
+            };
 
-        shm_file.set_len(size as u64)?;
+            if fd < 0 {
+                match errno() {
+                    Errno(libc::EEXIST) => continue,
+                    errno => return Err(Error::ShmOpen(errno)),
+                }
+            }
 
-        let data = unsafe { MmapOptions::new().map_mut(&shm_file)? };
+            let shm_file = unsafe { File::from_raw_fd(fd) };
 
-        Ok((Self(data), shm_file))
+            let res = unsafe { libc::shm_unlink(filename.as_ptr()) };
+            if res < 0 {
+                return Err(Error::ShmUnlink(errno()));
+            }
+
+            return Ok(shm_file);
+        }
+
+        Err(Error::NameCollision)
+    }
+
+    // unique within the process thanks to the counter, the time makes collisions with other
+    // processes unlikely
+    fn random_name() -> CString {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+
+        CString::new(format!("/wlclient-{}-{count}-{nanos:x}", process::id()))
+            .expect("The name has no nul bytes")
+    }
+
+    /// See [`PixelView::new`].
+    pub fn view(
+        &mut self,
+        format: WlShmFormat,
+        width: u32,
+        height: u32,
+        stride: usize,
+        offset: usize,
+    ) -> Result<PixelView<'_>> {
+        PixelView::new(&mut self.0, format, width, height, stride, offset)
+    }
+}
+
+/// Mutable view over the pixels of a buffer of shared memory.
+///
+/// Pixels are accessed as the bytes of the format (in memory order) or, for the formats of
+/// up to 32 bits, as an `u32` with the layout of the format (e.g. `0xAARRGGBB` for
+/// [`WlShmFormat::Argb8888`]).
+pub struct PixelView<'a> {
+    // from the first pixel to the last one
+    data: &'a mut [u8],
+    format: WlShmFormat,
+    width: u32,
+    height: u32,
+    stride: usize,
+}
+
+impl<'a> PixelView<'a> {
+    /// A view over a `width` x `height` buffer of `format` that starts at `offset` of `data`,
+    /// with lines of `stride` bytes.
+    pub fn new(
+        data: &'a mut [u8],
+        format: WlShmFormat,
+        width: u32,
+        height: u32,
+        stride: usize,
+        offset: usize,
+    ) -> Result<Self> {
+        let bits = format
+            .bits_per_pixel()
+            .filter(|bits| bits % 8 == 0)
+            .ok_or(Error::UnsupportedFormat(format))?;
+
+        if offset > data.len() {
+            return Err(Error::OutOfBounds { required: offset, available: data.len() });
+        }
+        let available = data.len() - offset;
+        // sizes that don't even fit in an usize
+        let overflow = || Error::OutOfBounds { required: usize::MAX, available };
+
+        let row_size = (width as usize).checked_mul(bits as usize / 8).ok_or_else(overflow)?;
+        if stride < row_size {
+            return Err(Error::OutOfBounds { required: row_size, available: stride });
+        }
+
+        let size = match height {
+            0 => Some(0),
+            height => (height as usize - 1)
+                .checked_mul(stride)
+                .and_then(|size| size.checked_add(row_size)),
+        };
+        let size = size.ok_or_else(overflow)?;
+        if size > available {
+            return Err(Error::OutOfBounds { required: size, available });
+        }
+
+        Ok(Self {
+            data: &mut data[offset..offset + size],
+            format,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn format(&self) -> WlShmFormat {
+        self.format
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.format.bits_per_pixel().unwrap_or_default() as usize / 8
+    }
+
+    fn row_size(&self) -> usize {
+        self.width as usize * self.bytes_per_pixel()
+    }
+
+    /// The pixels of the line `y`, `None` if it is outside the view.
+    pub fn row(&self, y: u32) -> Option<&[u8]> {
+        let start = y as usize * self.stride;
+        (y < self.height).then(|| &self.data[start..start + self.row_size()])
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> Option<&mut [u8]> {
+        let start = y as usize * self.stride;
+        let row_size = self.row_size();
+        (y < self.height).then(|| &mut self.data[start..start + row_size])
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let row_size = self.row_size();
+        self.data
+            .chunks(self.stride.max(1))
+            .take(self.height as usize)
+            .map(move |row| &row[..row_size])
+    }
+
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        let row_size = self.row_size();
+        self.data
+            .chunks_mut(self.stride.max(1))
+            .take(self.height as usize)
+            .map(move |row| &mut row[..row_size])
+    }
+
+    /// The bytes of the pixel at (`x`, `y`), `None` if it is outside the view.
+    pub fn pixel(&self, x: u32, y: u32) -> Option<&[u8]> {
+        let bytes = self.bytes_per_pixel();
+        let start = x as usize * bytes;
+        let row = self.row(y).filter(|_| x < self.width)?;
+        Some(&row[start..start + bytes])
     }
 
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> Option<&mut [u8]> {
+        let bytes = self.bytes_per_pixel();
+        let start = x as usize * bytes;
+        if x >= self.width {
+            return None;
+        }
+        Some(&mut self.row_mut(y)?[start..start + bytes])
+    }
+
+    /// Sets the pixel at (`x`, `y`) to `value`, returns `false` if it is outside the view.
+    ///
+    /// # Panics
+    ///
+    /// If the pixels of the format are larger than 32 bits.
+    pub fn set(&mut self, x: u32, y: u32, value: u32) -> bool {
+        let value = Self::pixel_bytes(self.format, value);
+        match self.pixel_mut(x, y) {
+            Some(pixel) => {
+                pixel.copy_from_slice(&value[..pixel.len()]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets every pixel of the view to `value`, see [`Self::set`].
+    pub fn fill(&mut self, value: u32) {
+        let value = Self::pixel_bytes(self.format, value);
+        let bytes = self.bytes_per_pixel();
+        for row in self.rows_mut() {
+            for pixel in row.chunks_exact_mut(bytes) {
+                pixel.copy_from_slice(&value[..bytes]);
+            }
+        }
+    }
+
+    /// The part of the view inside the `width` x `height` rectangle at (`x`, `y`), clipped to
+    /// the bounds of the view (so it might be empty).
+    pub fn sub_view(&mut self, x: u32, y: u32, width: u32, height: u32) -> PixelView<'_> {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+
+        // below the last line `start` would be past the end of the data (with a padded stride)
+        let data = match height {
+            0 => &mut [],
+            height => {
+                let bytes = self.bytes_per_pixel();
+                let start = y as usize * self.stride + x as usize * bytes;
+                let size = (height as usize - 1) * self.stride + width as usize * bytes;
+                &mut self.data[start..start + size]
+            }
+        };
+
+        PixelView {
+            data,
+            format: self.format,
+            width,
+            height,
+            stride: self.stride,
+        }
+    }
+
+    // pixels are stored in little endian
+    fn pixel_bytes(format: WlShmFormat, value: u32) -> [u8; 4] {
+        assert!(
+            format.bits_per_pixel().is_some_and(|bits| bits <= 32),
+            "{format:?} pixels don't fit in an u32"
+        );
+        value.to_le_bytes()
+    }
 }
 
 
@@ -57,3 +375,66 @@ impl DerefMut for SharedBuffer {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x2 pixels with 4 bytes of padding at the end of each line
+    const STRIDE: usize = 20;
+
+    fn padded_view(data: &mut [u8]) -> PixelView<'_> {
+        PixelView::new(data, WlShmFormat::Argb8888, 4, 2, STRIDE, 0).unwrap()
+    }
+
+    #[test]
+    fn sub_view_below_the_last_line_is_empty() {
+        let mut data = [0; STRIDE + 16];
+        let mut view = padded_view(&mut data);
+
+        let sub_view = view.sub_view(0, 2, 4, 1);
+        assert_eq!((sub_view.width(), sub_view.height()), (4, 0));
+        assert_eq!(sub_view.rows().count(), 0);
+
+        let sub_view = view.sub_view(1, 100, 100, 100);
+        assert_eq!((sub_view.width(), sub_view.height()), (3, 0));
+    }
+
+    #[test]
+    fn sub_view_is_clipped_to_the_view() {
+        let mut data = [0; STRIDE + 16];
+        let mut view = padded_view(&mut data);
+
+        let mut sub_view = view.sub_view(4, 1, 2, 2);
+        assert_eq!((sub_view.width(), sub_view.height()), (0, 1));
+        sub_view.fill(0xffffffff);
+
+        let mut sub_view = view.sub_view(3, 1, 2, 2);
+        assert_eq!((sub_view.width(), sub_view.height()), (1, 1));
+        sub_view.fill(0xffffffff);
+
+        assert_eq!(view.pixel(3, 1), Some(&[0xff; 4][..]));
+        assert_eq!(view.pixel(2, 1), Some(&[0; 4][..]));
+        assert!(view.row(0).unwrap().iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn new_rejects_an_offset_past_the_data() {
+        let mut data = [0; 16];
+        let view = PixelView::new(&mut data, WlShmFormat::Argb8888, 4, 0, 16, 17);
+        assert!(matches!(view, Err(Error::OutOfBounds { required: 17, available: 16 })));
+
+        let view = PixelView::new(&mut data, WlShmFormat::Argb8888, 4, 0, 16, 16);
+        assert!(view.is_ok_and(|view| view.rows().count() == 0));
+    }
+
+    #[test]
+    fn new_rejects_sizes_that_overflow() {
+        let mut data = [0; 16];
+        let view = PixelView::new(&mut data, WlShmFormat::Argb8888, 4, 3, usize::MAX, 0);
+        assert!(matches!(view, Err(Error::OutOfBounds { required: usize::MAX, .. })));
+
+        let view = PixelView::new(&mut data, WlShmFormat::Argb8888, u32::MAX, 1, usize::MAX, 0);
+        assert!(matches!(view, Err(Error::OutOfBounds { .. })));
+    }
+}
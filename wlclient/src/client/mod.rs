@@ -1,36 +1,130 @@
+pub mod damage;
+pub mod dispatch;
+pub mod dmabuf;
+mod listeners;
 pub mod memory;
+pub mod pool;
+pub mod region;
+pub mod registry;
+pub mod seat;
+pub mod single_pixel;
+pub mod stats;
+pub mod swapchain;
+#[cfg(test)]
+mod test_server;
+pub mod unhandled;
 
 use std::{
     any::Any,
-    cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     env,
-    io::Read,
-    os::{fd::AsRawFd, unix::net::UnixStream},
-    rc::Rc,
+    io::IoSliceMut,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::{AncillaryData, SocketAncillary, UnixStream},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 use crate::{
     error::{error_context, fallback_error, Error, Result},
     protocol::{base::*, xdg_shell::*, *},
+    sync::{Lock, MaybeSend, Shared},
 };
 
-use log::{error, info, trace, warn};
+use listeners::{ListenerList, Listeners};
+use log::{error, trace, warn};
 use memory::SharedBuffer;
+use registry::{GlobalInfo, GlobalRegistry};
+use seat::Seats;
+use stats::ConnectionStats;
+use unhandled::{UnhandledEvents, UnhandledPolicy};
 
 pub struct WaylandClient<S = ()> {
-    globals: HashMap<WlInterfaceId, WaylandId>,
-    objects: WlObjectManager<S>,
-    stream: Rc<ClientStream>,
+    socket_path: String,
+    // every bound instance of each interface, by the order they were bound
+    globals: HashMap<WlInterfaceId, Vec<WaylandId>>,
+    objects: Shared<Lock<WlObjectManager<S>>>,
+    stream: Shared<ClientStream>,
     socket: UnixStream,
     buffer: ByteBuffer,
-    state: Option<S>,
+    queues: HashMap<EventQueueId, VecDeque<RawMessage>>,
+    queues_id_count: EventQueueId,
+    unhandled: Lock<UnhandledEvents<S>>,
+    registry: Lock<GlobalRegistry<S>>,
+    seats: Lock<Seats<S>>,
+    stats: ConnectionStats,
+    // formats advertised through wl_shm.format
+    shm_formats: HashSet<u32>,
+    // see `set_core_interface_handler`
+    core_handlers: Lock<HashMap<WlInterfaceId, Box<dyn CoreInterfaceHandler<S>>>>,
+}
+
+const WL_DISPLAY_ID: WaylandId = 1;
+const WL_DISPLAY_ERROR_EVENT: u16 = 0;
+
+type EventQueueId = u32;
+const DEFAULT_QUEUE_ID: EventQueueId = 0;
+
+/// Handle to an event queue of a [`WaylandClient`].
+///
+/// Every object belongs to exactly one queue (the default one unless stated otherwise) and
+/// its events are only dispatched when that queue is dispatched. This allows, for example,
+/// a library to do its own roundtrips without running the application handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventQueue(EventQueueId);
+
+/// Identifies a single listener of an object, see [`WaylandClient::add_event_listener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ListenerId {
+    object_id: WaylandId,
+    id: u32,
+}
+
+/// Cheap to clone handle to the objects of a [`WaylandClient`].
+///
+/// With the `thread-safe` feature it is `Send + Sync`, so worker threads can create objects
+/// and send requests while another thread dispatches the events of the client.
+pub struct ClientHandle<S = ()> {
+    objects: Shared<Lock<WlObjectManager<S>>>,
+    stream: StreamRef,
+}
+
+impl<S> Clone for ClientHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            objects: Shared::clone(&self.objects),
+            stream: Shared::clone(&self.stream),
+        }
+    }
+}
+
+impl<S> ClientHandle<S> {
+    pub fn new_object<T: WlInterface<Event = E>, E: Sized + 'static>(&self) -> T {
+        self.objects.lock().new_proxy(self.stream.clone(), None)
+    }
+
+    pub fn new_object_with_data<T, E, D>(&self, data: D) -> T
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+        D: AnyData,
+    {
+        self.objects.lock().new_proxy(self.stream.clone(), Some(Shared::new(data)))
+    }
+
+    pub fn get_reference<T: WlInterface<Event = E>, E>(&self, object_id: u32) -> Option<T> {
+        self.objects.lock().get_reference(object_id, self.stream.clone())
+    }
 }
 
 // TODO:
 // - Think about if you really want to keep the lifetime
-// - Rethink about interface to interact with the state
 // - Should handler function return `Result<(), Error>`??
+//
+// The application state `S` isn't owned by the client, it is lent to it by the dispatch
+// functions and handed to the listeners alongside the client itself.
 impl<S> WaylandClient<S> {
     pub fn connect() -> Result<Self> {
         // TODO: should I add: 'Failed to build from default'?
@@ -38,109 +132,393 @@ impl<S> WaylandClient<S> {
     }
 
     pub fn connect_to(socket_path: &str) -> Result<Self> {
-        log::debug!("Connecting to socket_path = {socket_path}");
-        let socket = error_context!(
-            UnixStream::connect(socket_path),
-            "Failed to establish connection."
-        )?;
+        let socket = Self::open_socket(socket_path)?;
 
         let mut client = Self {
-            objects: WlObjectManager::new(),
+            socket_path: socket_path.to_string(),
+            objects: Shared::new(Lock::new(WlObjectManager::new())),
             globals: HashMap::new(),
             buffer: ByteBuffer::new(4 * 1024),
-            stream: Rc::new(ClientStream::new(
+            stream: Shared::new(ClientStream::new(
                 socket.try_clone().expect("Unable to clone UnixStream"),
             )),
             socket,
-            state: None,
+            queues: HashMap::from([(DEFAULT_QUEUE_ID, VecDeque::new())]),
+            queues_id_count: DEFAULT_QUEUE_ID,
+            unhandled: Lock::new(UnhandledEvents::new()),
+            registry: Lock::new(GlobalRegistry::new()),
+            seats: Lock::new(Seats::new()),
+            stats: ConnectionStats::default(),
+            shm_formats: HashSet::new(),
+            core_handlers: Lock::new(HashMap::new()),
         };
 
-        client.init_globals()?;
+        // the devices of the seats are created for every application, even those that don't
+        // care about (some of) their events
+        client.set_interface_unhandled_policy::<WlPointer>(UnhandledPolicy::Ignore);
+        client.set_interface_unhandled_policy::<WlKeyboard>(UnhandledPolicy::Ignore);
+        client.set_interface_unhandled_policy::<WlTouch>(UnhandledPolicy::Ignore);
+
+        client.init_globals(None)?;
         Ok(client)
     }
 
-    pub fn get_custom_state(&mut self) -> Option<&mut S> {
-        self.state.as_mut()
+    /// Opens a new connection to the compositor (e.g. after it restarted) and discovers its
+    /// globals again, the global listeners are notified of every one of them.
+    ///
+    /// Every object of the previous connection is gone, so their proxies (as well as the
+    /// handles of the client) become unusable and the application has to create its objects
+    /// once again. The event queues, the global and seat listeners and the unhandled events
+    /// policies are kept.
+    pub fn reconnect(&mut self, state: &mut S) -> Result<()> {
+        let socket = Self::open_socket(&self.socket_path)?;
+
+        self.stream.set_disconnected();
+        self.stream = Shared::new(ClientStream::new(
+            socket.try_clone().expect("Unable to clone UnixStream"),
+        ));
+        self.socket = socket;
+        self.objects = Shared::new(Lock::new(WlObjectManager::new()));
+        self.globals.clear();
+        self.buffer = ByteBuffer::new(4 * 1024);
+        self.queues.values_mut().for_each(VecDeque::clear);
+        self.registry.lock().reset();
+        self.seats.lock().reset();
+        self.stats = ConnectionStats::default();
+        self.shm_formats.clear();
+
+        self.init_globals(Some(state))
     }
 
-    pub fn set_custom_state(&mut self, state: S) {
-        self.state = Some(state)
+    /// Whether the connection is still usable (i.e. neither closed by the server nor broken by
+    /// a protocol error).
+    pub fn is_connected(&self) -> bool {
+        !self.stream.is_disconnected() && self.stream.get_fatal_error().is_none()
     }
 
-    pub fn get_global<T: WlInterface<Event = E>, E>(&self) -> Option<T> {
-        self.get_reference(self.globals.get(&T::get_interface_id()).copied()?)
+    fn open_socket(socket_path: &str) -> Result<UnixStream> {
+        log::debug!("Connecting to socket_path = {socket_path}");
+        error_context!(
+            UnixStream::connect(socket_path),
+            "Failed to establish connection."
+        )
     }
 
-    // TODO: use actual enumerate for errors
-    pub fn upgrade_to_global<T: WlInterface<Event = E>, E>(&mut self, object: &T) -> Result<()> {
-        let object_id = object.get_object_id();
-        let Some(interface_id) = self.objects.get_object_interface(object_id) else {
-            return Err(fallback_error!("No such object"));
-        };
-
-        if interface_id != T::get_interface_id() {
-            return Err(fallback_error!("Invalid object interface"));
+    pub fn handle(&self) -> ClientHandle<S> {
+        ClientHandle {
+            objects: Shared::clone(&self.objects),
+            stream: self.stream.clone(),
         }
+    }
 
-        // TODO: think about this c:
-        if self.globals.contains_key(&interface_id) {
-            return Err(fallback_error!(
-                "Global of '{}' already registered",
-                T::get_display_name()
-            ));
-        }
+    /// The first bound instance of the global `T`.
+    pub fn get_global<T: WlInterface<Event = E>, E>(&self) -> Option<T> {
+        self.get_reference(*self.globals.get(&T::get_interface_id())?.first()?)
+    }
 
-        let _ = self.globals.insert(interface_id, object_id);
-        Ok(())
+    /// Every bound instance of the global `T`.
+    pub fn get_globals<T: WlInterface<Event = E>, E>(&self) -> Vec<T> {
+        self.globals
+            .get(&T::get_interface_id())
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.get_reference(*id))
+            .collect()
     }
 
     pub fn get_reference<T: WlInterface<Event = E>, E>(&self, object_id: u32) -> Option<T> {
-        match self.objects.get_object_interface(object_id) {
-            Some(interface_id) if interface_id == T::get_interface_id() => {
-                Some(T::build(object_id, self.stream.clone()))
-            }
-            _ => None,
-        }
+        self.objects.lock().get_reference(object_id, self.stream.clone())
+    }
+
+    /// Appends `listener` to the listeners chain of `object`.
+    ///
+    /// When an event arrives, the listeners are called by the order they were added. A listener
+    /// consumes the event by returning `None` or forwards it to the next one by returning it back.
+    pub fn add_event_listener<T, E, F>(&mut self, object: &T, mut listener: F) -> Result<ListenerId>
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut S, &mut WaylandClient<S>, WlEventMsg<E>) -> Option<WlEventMsg<E>>
+            + MaybeSend
+            + 'static,
+        E: 'static,
+    {
+        self.add_listener(object, move |client, state, msg| {
+            listener(state.expect(NO_STATE), client, msg)
+        })
+    }
+
+    /// Appends a listener that consumes every event it receives to the listeners chain of `object`.
+    pub fn add_event_handler<T, E, F>(&mut self, object: &T, mut handler: F) -> Result<ListenerId>
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut S, &mut WaylandClient<S>, WlEventMsg<E>) + MaybeSend + 'static,
+        E: 'static,
+    {
+        self.add_event_listener(object, move |state, client, msg| {
+            handler(state, client, msg);
+            None
+        })
+    }
+
+    // Handlers of the client itself, which have to work without the application state (e.g.
+    // while connecting).
+    fn add_core_handler<T, E, F>(&mut self, object: &T, mut handler: F) -> Result<ListenerId>
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut WaylandClient<S>, Option<&mut S>, WlEventMsg<E>) + MaybeSend + 'static,
+        E: 'static,
+    {
+        self.add_listener(object, move |client, state, msg| {
+            handler(client, state, msg);
+            None
+        })
     }
 
-    pub fn add_event_handler<T, E, F>(&mut self, object: &T, mut handler: F) -> Result<()>
+    // Handler of the client itself for the events of every object of the interface `T`. It
+    // only observes the events: it runs before the listeners of the object, which get the
+    // events afterwards, and the events nothing else consumed aren't unhandled.
+    fn set_core_interface_handler<T, E, F>(&mut self, mut handler: F)
     where
         T: WlInterface<Event = E>,
-        F: FnMut(&mut WaylandClient<S>, WlEventMsg<E>) + 'static,
+        F: FnMut(&mut WaylandClient<S>, &WlEventMsg<E>) + MaybeSend + 'static,
+        E: 'static,
+    {
+        self.core_handlers.lock().insert(
+            T::get_interface_id(),
+            Box::new(move |client, msg| match msg.downcast_ref() {
+                Some(msg) => handler(client, msg),
+                None => panic!("Unable to get WlEventMsg<...> for {}", T::get_display_name()),
+            }),
+        );
+    }
+
+    fn add_listener<T, E, F>(&mut self, object: &T, mut listener: F) -> Result<ListenerId>
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut WaylandClient<S>, Option<&mut S>, WlEventMsg<E>) -> Option<WlEventMsg<E>>
+            + MaybeSend
+            + 'static,
         E: 'static,
     {
         let object_id = object.get_object_id();
 
-        self.objects.add_handler(
+        let id = self.objects.lock().add_listener(
             object_id,
             T::get_interface_id(),
-            Box::new(move |client, msg| match WlEventMsg::from_any(msg) {
+            Box::new(move |client, state, msg| match WlEventMsg::from_any(msg) {
                 Some(msg) => {
                     assert_eq!(object_id, msg.object_id);
-                    handler(client, msg);
+                    listener(client, state, msg).map(WlEventMsg::to_any)
                 }
                 None => panic!("Unable to get WlEventMsg<...> for object {object_id}"),
             }),
-        )
+        )?;
+
+        Ok(ListenerId { object_id, id })
+    }
+
+    /// Removes a single listener. If the listener's object is dispatching an event, the removal
+    /// only takes effect after all its listeners have been called.
+    pub fn remove_event_listener(&mut self, listener: ListenerId) -> Result<()> {
+        self.objects.lock().remove_listener(listener.object_id, listener.id)
     }
 
+    /// Removes every listener of `object`.
     pub fn remove_event_handler<T: WlInterface<Event = E>, E>(&mut self, object: &T) -> Result<()> {
-        self.objects.remove_handler(object.get_object_id())
+        self.objects.lock().remove_listeners(object.get_object_id())
     }
 
-    pub fn event_loop(mut self) {
+    /// Dispatches events until the connection becomes unusable, returning the error that made
+    /// it so. Any other error is only logged.
+    ///
+    /// The client is kept, so that it can [`Self::reconnect`] and resume the loop.
+    pub fn event_loop(&mut self, state: &mut S) -> Result<()> {
         loop {
-            match self.next_msg() {
-                Err(err) => error!("Reading message from the wire: {err:#?}!"),
-                Ok(msg) => {
-                    if let Err(err) = self.handle_msg(msg) {
-                        warn!("Error handling message message: {err:?}")
-                    }
-                }
+            match self.dispatch(state) {
+                Err(err) if err.is_fatal() => return Err(err),
+                Err(err) => warn!("Error dispatching events: {err:?}"),
+                Ok(_) => (),
             }
         }
     }
 
+    pub fn default_queue(&self) -> EventQueue {
+        EventQueue(DEFAULT_QUEUE_ID)
+    }
+
+    pub fn new_event_queue(&mut self) -> EventQueue {
+        self.queues_id_count += 1;
+        let queue_id = self.queues_id_count;
+        assert!(self.queues.insert(queue_id, VecDeque::new()).is_none());
+        EventQueue(queue_id)
+    }
+
+    /// Destroys `queue`, the objects assigned to it (as well as its pending events) are moved
+    /// back to the default queue.
+    pub fn destroy_event_queue(&mut self, queue: EventQueue) -> Result<()> {
+        if queue.0 == DEFAULT_QUEUE_ID {
+            return Err(fallback_error!("The default queue cannot be destroyed"));
+        }
+
+        let pending = self.queues.remove(&queue.0).ok_or(Error::NoSuchQueue)?;
+        self.objects.lock().reassign_queue(queue.0, DEFAULT_QUEUE_ID);
+        self.queue_mut(DEFAULT_QUEUE_ID).extend(pending);
+        Ok(())
+    }
+
+    pub fn set_object_queue<T: WlInterface<Event = E>, E>(
+        &mut self,
+        object: &T,
+        queue: EventQueue,
+    ) -> Result<()> {
+        if !self.queues.contains_key(&queue.0) {
+            return Err(Error::NoSuchQueue);
+        }
+        self.objects.lock().set_object_queue(object.get_object_id(), queue.0)
+    }
+
+    /// Dispatches the events already waiting in the default queue, without reading the socket.
+    pub fn dispatch_pending(&mut self, state: &mut S) -> Result<usize> {
+        self.dispatch_queue_pending(self.default_queue(), state)
+    }
+
+    /// Blocks until at least one event of the default queue is dispatched.
+    pub fn dispatch(&mut self, state: &mut S) -> Result<usize> {
+        self.dispatch_queue(self.default_queue(), state)
+    }
+
+    /// Blocks until the server has processed every request sent so far, dispatching the events
+    /// of the default queue in the meantime.
+    pub fn roundtrip(&mut self, state: &mut S) -> Result<()> {
+        self.roundtrip_queue(self.default_queue(), state)
+    }
+
+    pub fn dispatch_queue_pending(&mut self, queue: EventQueue, state: &mut S) -> Result<usize> {
+        self.dispatch_queue_pending_with(queue, Some(state))
+    }
+
+    pub fn dispatch_queue(&mut self, queue: EventQueue, state: &mut S) -> Result<usize> {
+        self.dispatch_queue_with(queue, Some(state), None)
+    }
+
+    pub fn roundtrip_queue(&mut self, queue: EventQueue, state: &mut S) -> Result<()> {
+        self.roundtrip_queue_with(queue, Some(state), None)
+    }
+
+    /// Like [`Self::dispatch`], but gives up with [`Error::Timeout`] (see [`Error::is_timeout`]) if
+    /// no event of the default queue could be dispatched within `timeout`. A message that was only
+    /// partially received is kept for the next call.
+    pub fn dispatch_timeout(&mut self, timeout: Duration, state: &mut S) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        self.dispatch_queue_with(self.default_queue(), Some(state), Some(deadline))
+    }
+
+    /// Like [`Self::roundtrip`], but gives up with [`Error::Timeout`] if the server hasn't
+    /// answered within `timeout`. The events received until then stay queued.
+    pub fn roundtrip_timeout(&mut self, timeout: Duration, state: &mut S) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.roundtrip_queue_with(self.default_queue(), Some(state), Some(deadline))
+    }
+
+    fn dispatch_queue_pending_with(
+        &mut self,
+        queue: EventQueue,
+        mut state: Option<&mut S>,
+    ) -> Result<usize> {
+        if !self.queues.contains_key(&queue.0) {
+            return Err(Error::NoSuchQueue);
+        }
+
+        let mut dispatched = 0;
+        // the queue might be destroyed by one of the handlers
+        while let Some(msg) = self.queues.get_mut(&queue.0).and_then(VecDeque::pop_front) {
+            self.check_fatal_error()?;
+            self.handle_msg(msg, state.as_deref_mut())?;
+            dispatched += 1;
+        }
+
+        Ok(dispatched)
+    }
+
+    // `deadline` bounds the time spent waiting for the socket, `None` blocks until an event comes
+    fn dispatch_queue_with(
+        &mut self,
+        queue: EventQueue,
+        mut state: Option<&mut S>,
+        deadline: Option<Instant>,
+    ) -> Result<usize> {
+        loop {
+            self.check_fatal_error()?;
+            let pending = self.queues.get(&queue.0).ok_or(Error::NoSuchQueue)?;
+            if !pending.is_empty() {
+                return self.dispatch_queue_pending_with(queue, state);
+            }
+
+            let msg = self.next_msg(deadline)?;
+            if msg.object_id == WL_DISPLAY_ID && msg.event_id == WL_DISPLAY_ERROR_EVENT {
+                // handled right away, whatever queue is being dispatched
+                self.handle_msg(msg, state.as_deref_mut())?;
+                continue;
+            }
+
+            let queue_id = self
+                .objects
+                .lock()
+                .get_object_queue(msg.object_id)
+                .unwrap_or(DEFAULT_QUEUE_ID);
+            self.queue_mut(queue_id).push_back(msg);
+        }
+    }
+
+    fn roundtrip_queue_with(
+        &mut self,
+        queue: EventQueue,
+        mut state: Option<&mut S>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let display: WlDisplay = self.get_global().expect("Failed to get global WlDisplay");
+        let callback: WlCallBack = self.new_object_in_queue(queue)?;
+
+        let start = Instant::now();
+        let completed = Shared::new(AtomicBool::new(false));
+        {
+            let flag = Shared::clone(&completed);
+            self.add_core_handler(&callback, move |_, _, _| flag.store(true, Ordering::Relaxed))?;
+        }
+
+        display.sync(&callback)?;
+        while !completed.load(Ordering::Relaxed) {
+            self.dispatch_queue_with(queue, state.as_deref_mut(), deadline)?;
+        }
+
+        self.stats.record_roundtrip(start.elapsed());
+
+        Ok(())
+    }
+
+    /// Returns the protocol error sent by the server, if any. Once it is set the connection
+    /// cannot be used anymore.
+    pub fn protocol_error(&self) -> Option<ProtocolError> {
+        self.stream.get_fatal_error()
+    }
+
+    fn check_fatal_error(&self) -> Result<()> {
+        if self.stream.is_disconnected() {
+            return Err(crate::protocol::Error::Disconnected.into());
+        }
+
+        match self.stream.get_fatal_error() {
+            Some(error) => Err(crate::protocol::Error::Fatal(error).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn queue_mut(&mut self, queue_id: EventQueueId) -> &mut VecDeque<RawMessage> {
+        self.queues
+            .get_mut(&queue_id)
+            .expect("Objects should only be assigned to existing queues")
+    }
+
     pub fn create_pool(&mut self, size: i32) -> Result<(WlShmPool, SharedBuffer)> {
         assert!(size > 0);
 
@@ -156,142 +534,261 @@ impl<S> WaylandClient<S> {
         Ok((pool, buffer))
     }
 
-    fn init_globals(&mut self) -> Result<()> {
+    fn init_globals(&mut self, mut state: Option<&mut S>) -> Result<()> {
         let display: WlDisplay = self.new_global();
-        assert!(display.get_object_id() == 1);
+        assert!(display.get_object_id() == WL_DISPLAY_ID);
 
-        self.add_event_handler(&display, |client, msg| match msg.event {
+        self.add_core_handler(&display, |client, _, msg| match msg.event {
             WlDisplayEvent::Error {
                 object_id,
                 code,
                 message,
-            } => error!("Wayland error {code} for object {object_id}: {message:?}"), // TODO: add more context c: (display the object interface)
-            WlDisplayEvent::DeleteId { id } => match client.objects.get_object_entry_copy(id) {
-                Some(obj) => {
-                    log::debug!("Delecting object {id} @ {}", obj.interface.display_name);
-                    client.objects.remove_object(id);
+            } => {
+                let interface = client.objects.lock().get_object_interface_info(object_id);
+                let error = ProtocolError {
+                    object_id,
+                    interface: interface.map_or("", |i| i.wire_name),
+                    code,
+                    code_name: interface.and_then(|i| (i.error_name)(code)),
+                    message,
+                };
+                error!("{error}");
+                client.stream.set_fatal_error(error);
+            }
+            WlDisplayEvent::DeleteId { id } if !(CLIENT_MIN_ID..=CLIENT_MAX_ID).contains(&id) => {
+                log::error!("Received delete for object {id} which is outside of the client id range")
+            }
+            WlDisplayEvent::DeleteId { id } => {
+                // The events the object got before it was deleted might still wait in another
+                // queue (or for its listeners), its id is only released once they are dispatched.
+                if client.has_pending_events(id) {
+                    if let Some(entry) = client.objects.lock().get_object_entry_mut(id) {
+                        entry.deleted = true;
+                    }
+                } else {
+                    client.delete_object(id);
                 }
-                None => log::error!("Received delete for a non existant object {id}"),
-            },
+            }
         })?;
 
+        self.set_core_interface_handler::<WlShm, _, _>(|client, msg| {
+            let WlShmEvent::Format { format_value } = msg.event;
+            client.shm_formats.insert(format_value);
+        });
+
         let registry: WlRegistry = self.new_global();
         display.get_registry(&registry)?;
 
-        self.add_event_handler(&registry, |client, msg| {
-            let WlRegistryEvent::Global {
+        self.add_core_handler(&registry, |client, mut state, msg| match msg.event {
+            WlRegistryEvent::Global {
                 name,
                 interface,
                 version,
-            } = msg.event;
-
-            let object_id = match interface.as_str() {
-                "wl_compositor" => client.new_global_id::<WlCompositor, _>(),
-                "xdg_wm_base" => {
-                    let wm: XdgWmBase = client.new_global();
-                    client
-                        .add_event_handler(&wm, |client, msg| {
-                            let wm: XdgWmBase = client.get_reference(msg.object_id).unwrap();
-                            let XdgWmBaseEvent::Ping { serial } = msg.event;
-                            wm.pong(serial).unwrap();
-                        })
-                        .unwrap();
+            } => {
+                // every seat is bound, so that its devices are tracked
+                let is_seat = interface == WlSeat::get_interface_name();
+                let global = GlobalInfo {
+                    name,
+                    interface,
+                    version,
+                };
+                client.global_added(global, state.as_deref_mut());
 
-                    wm.get_object_id()
-                }
-                "wl_shm" => {
-                    let shm: WlShm = client.new_global();
-
-                    // Ignore shm messages, I don't really care about those, since Argb and Xrgb
-                    // are mandatory to be implemented by the server, so I am cool c:
-                    // This is to surpress the warning messages: 'No handler for object ...'
-                    client.add_event_handler(&shm, |_, _| {}).unwrap();
-                    shm.get_object_id()
+                if is_seat {
+                    if let Err(err) = client.seat_added(name, state) {
+                        warn!("Failed to bind wl_seat ({name}): {err:?}");
+                    }
                 }
-                _ => return,
-            };
-
-            info!("Mapping {interface} to global");
-            let registry: WlRegistry = client.get_global().unwrap();
-            registry.bind(name, interface, version, object_id).unwrap(); // TODO: add proper error message
+            }
+            WlRegistryEvent::GlobalRemove { name } => {
+                client.seat_removed(name, state.as_deref_mut());
+                client.global_removed(name, state);
+            }
         })?;
 
-        let callback: WlCallBack = self.new_object();
-        display.sync(&callback)?;
-
-        let completed = Rc::new(RefCell::new(false));
-        {
-            let flag = Rc::clone(&completed);
-            self.add_event_handler(&callback, move |_, _| {
-                *flag.borrow_mut() = true;
-            })
-            .unwrap();
-        }
+        // every wm base has to answer the pings, even those bound by the application
+        self.set_core_interface_handler::<XdgWmBase, _, _>(|client, msg| {
+            let wm: XdgWmBase = client.get_reference(msg.object_id).unwrap();
+            let XdgWmBaseEvent::Ping { serial } = msg.event;
+            if let Err(err) = wm.pong(serial) {
+                warn!("Failed to answer ping {serial}: {err:?}");
+            }
+        });
 
-        while !*completed.borrow() {
-            let msg = self.next_msg()?;
-            self.handle_msg(msg)?;
-        }
+        self.roundtrip_queue_with(self.default_queue(), state.as_deref_mut(), None)?;
 
-        Ok(())
-    }
+        self.bind_first::<WlCompositor, _>()?;
+        self.bind_first::<XdgWmBase, _>()?;
+        self.bind_first::<WlShm, _>()?;
 
-    #[inline(always)]
-    fn new_global_id<T: WlInterface<Event = E>, E: Sized + 'static>(&mut self) -> WaylandId {
-        self.new_global::<T, E>().get_object_id()
+        // for the events sent when the globals are bound (e.g. the shm formats)
+        self.roundtrip_queue_with(self.default_queue(), state, None)
     }
 
+    // for the objects that are globals without being advertised (wl_display and wl_registry)
     fn new_global<T: WlInterface<Event = E>, E: Sized + 'static>(&mut self) -> T {
         let object: T = self.new_object();
-        assert!(
-            self.globals
-                .insert(T::get_interface_id(), object.get_object_id())
-                .is_none(),
-            "Creating global twice :c"
-        );
+        self.globals
+            .entry(T::get_interface_id())
+            .or_default()
+            .push(object.get_object_id());
         object
     }
 
     pub fn new_object<T: WlInterface<Event = E>, E: Sized + 'static>(&mut self) -> T {
-        let object_id = self.objects.new_object(
-            WlObjectInterfaceInfo {
-                id: T::get_interface_id(),
-                display_name: T::get_display_name(),
-            },
-            Box::new(|raw_msg| Ok(T::parse_msg(raw_msg).map(|value| value.to_any())?)),
-        );
+        self.objects.lock().new_proxy(self.stream.clone(), None)
+    }
+
+    /// Creates an object with `data` attached to it, which can be retrieved from any of its
+    /// proxies and from the events it receives.
+    pub fn new_object_with_data<T, E, D>(&mut self, data: D) -> T
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+        D: AnyData,
+    {
+        self.objects.lock().new_proxy(self.stream.clone(), Some(Shared::new(data)))
+    }
+
+    pub fn new_object_in_queue<T: WlInterface<Event = E>, E: Sized + 'static>(
+        &mut self,
+        queue: EventQueue,
+    ) -> Result<T> {
+        if !self.queues.contains_key(&queue.0) {
+            return Err(Error::NoSuchQueue);
+        }
+
+        let object: T = self.new_object();
+        self.objects.lock().set_object_queue(object.get_object_id(), queue.0)?;
+        Ok(object)
+    }
+
+    // Removing the object releases its id, which another thread could then reuse right away, so
+    // everything else must forget it first.
+    fn delete_object(&mut self, object_id: WaylandId) {
+        self.stream.forget_object(object_id);
+        self.forget_global(object_id);
+        match self.objects.lock().remove_object(object_id) {
+            Some(obj) => log::debug!("Delecting object {object_id} @ {}", obj.interface.display_name),
+            None => log::error!("Received delete for a non existant object {object_id}"),
+        }
+    }
 
-        T::build(object_id, self.stream.clone())
+    fn has_pending_events(&self, object_id: WaylandId) -> bool {
+        let notifying = self
+            .objects
+            .lock()
+            .get_object_entry_mut(object_id)
+            .is_some_and(|entry| entry.listeners.is_notifying());
+        notifying || self.queues.values().flatten().any(|msg| msg.object_id == object_id)
     }
 
-    fn handle_msg(&mut self, msg: RawMessage) -> Result<()> {
+    fn handle_msg(&mut self, msg: RawMessage, mut state: Option<&mut S>) -> Result<()> {
         let object_id = msg.object_id;
-        let event_id = msg.event_id;
+        {
+            let mut objects = self.objects.lock();
+            let entry = objects.get_object_entry_mut(object_id).ok_or_else(|| {
+                fallback_error!("Received message to an non-existant object: {object_id}")
+            })?;
+
+            // The listeners of the object are running (the event was read by one of them, e.g.
+            // during a roundtrip), the event is dispatched once they are over.
+            if !entry.listeners.queue(msg) {
+                return Ok(());
+            }
+        }
+
+        let mut result = Ok(());
+        loop {
+            let next = {
+                let mut objects = self.objects.lock();
+                // the object might have been deleted by one of its listeners
+                objects.get_object_entry_mut(object_id).and_then(|e| e.listeners.next_event())
+            };
+            let Some((msg, mut listeners)) = next else {
+                break;
+            };
+
+            let dispatched = self.dispatch_event(msg, &mut listeners, state.as_deref_mut());
+            if let Some(entry) = self.objects.lock().get_object_entry_mut(object_id) {
+                entry.listeners.restore(listeners);
+            }
+            // the first error is kept, but the other events are still dispatched
+            result = result.and(dispatched);
+        }
 
-        let mut entry = self
+        let deleted = self
             .objects
-            .get_object_entry_copy(msg.object_id)
-            .ok_or_else(|| fallback_error!("Received message to an non-existant object: {object_id}"))?;
+            .lock()
+            .get_object_entry_mut(object_id)
+            .is_some_and(|entry| entry.deleted);
+        if deleted && !self.has_pending_events(object_id) {
+            self.delete_object(object_id);
+        }
+        result
+    }
 
-        let interface_id = entry.interface.id;
-        let display_name = entry.interface.display_name;
+    fn dispatch_event(
+        &mut self,
+        msg: RawMessage,
+        listeners: &mut ObjectListeners<S>,
+        mut state: Option<&mut S>,
+    ) -> Result<()> {
+        let object_id = msg.object_id;
+        let event_id = msg.event_id;
 
-        // TODO: add a few methods for the type entry c: (like parse and take c:)
-        let msg = error_context!((entry.parser)(msg), "Of object {object_id} @ {display_name}")?;
-        let mut handler = entry.handler.take().ok_or_else(|| {
-            fallback_error!("No handler object {object_id} @ {display_name} (received event {event_id})")
-        })?;
+        let (interface, msg) = {
+            let mut objects = self.objects.lock();
+            let entry = objects.get_object_entry_mut(object_id).ok_or(Error::NoSuchObject)?;
+
+            let display_name = entry.interface.display_name;
+            let msg = error_context!(
+                (entry.event_parser)(msg, entry.user_data.clone()),
+                "Of object {object_id} @ {display_name}"
+            )?;
+
+            if self.stream.is_dead(object_id) {
+                // The event raced with the destruction of the object, dropping it also closes
+                // the file descriptors it might carry.
+                log::debug!("Discarding event {event_id} of destroyed object {object_id} @ {display_name}");
+                return Ok(());
+            }
 
-        handler(self, msg);
-        let _ = self.objects.add_handler(object_id, interface_id, handler); // try to restore handler
-        Ok(())
+            (entry.interface, msg)
+        };
+
+        let start = Instant::now();
+        let core_handler = self.core_handlers.lock().remove(&interface.id);
+        if let Some(mut handler) = core_handler {
+            handler(self, msg.as_ref());
+            self.core_handlers.lock().insert(interface.id, handler);
+        }
+
+        let mut msg = Some(msg);
+        for (_, listener) in listeners.iter_mut() {
+            msg = listener(self, state.as_deref_mut(), msg.take().unwrap());
+            if msg.is_none() {
+                break;
+            }
+        }
+
+        let result = match msg {
+            Some(msg) => self.handle_unhandled(object_id, interface, event_id, msg, state),
+            None => Ok(()),
+        };
+
+        self.stats.record_handler(interface.wire_name, start.elapsed());
+        result
     }
 
-    fn next_msg(&mut self) -> Result<RawMessage> {
-        let header = WireMsgHeader::build(error_context!(
-            self.read_bytes(WireMsgHeader::WIRE_SIZE),
+    fn next_msg(&mut self, deadline: Option<Instant>) -> Result<RawMessage> {
+        // nothing is consumed until the whole message was received, so that a timeout doesn't
+        // lose the part that was already read
+        error_context!(
+            self.fill_buffer(WireMsgHeader::WIRE_SIZE, deadline),
             "Failed to read wire message header"
-        )?);
+        )?;
+        let header = WireMsgHeader::build(self.buffer.peek(WireMsgHeader::WIRE_SIZE));
 
         trace!(
             "Received a msg from {} with {} size",
@@ -299,20 +796,44 @@ impl<S> WaylandClient<S> {
             header.length
         );
 
-        let payload = error_context!(
-            self.read_bytes(header.length as usize - WireMsgHeader::WIRE_SIZE),
+        if (header.length as usize) < WireMsgHeader::WIRE_SIZE {
+            let error = crate::protocol::Error::InvalidMessageSize(header.length as usize);
+            return error_context!(Err(error), "Of object {}", header.object_id);
+        }
+
+        error_context!(
+            self.fill_buffer(header.length as usize, deadline),
             "Failed to read message payload"
         )?;
+        let payload = self.buffer.consume(header.length as usize)[WireMsgHeader::WIRE_SIZE..].into();
+
+        // The file descriptors aren't part of the payload, they have to be taken (by the order
+        // they were received) when the message is read, even if its dispatch is delayed.
+        let (fds_count, interface) = {
+            let objects = self.objects.lock();
+            let interface = objects.get_object_interface_info(header.object_id);
+            (
+                objects.get_event_fds(header.object_id, header.method_id),
+                interface.map_or("", |i| i.wire_name),
+            )
+        };
+
+        self.stats.record_received(interface, header.method_id, header.length as usize, fds_count);
 
         Ok(RawMessage {
             object_id: header.object_id,
             event_id: header.method_id,
-            payload: payload.into(),
+            payload,
+            fds: self.buffer.take_fds(fds_count),
         })
     }
 
-    fn read_bytes(&mut self, size: usize) -> Result<&[u8]> {
-        self.buffer.read_bytes(size, &mut self.socket)
+    fn fill_buffer(&mut self, size: usize, deadline: Option<Instant>) -> Result<()> {
+        let result = self.buffer.fill(size, &self.socket, deadline);
+        if matches!(result, Err(Error::WlProtocolError(crate::protocol::Error::Disconnected))) {
+            self.stream.set_disconnected();
+        }
+        result
     }
 }
 
@@ -323,37 +844,61 @@ pub fn get_wayland_socket_path() -> Result<String> {
     Ok(format!("{xdg_dir}/{socket_file}"))
 }
 
-type MockingHandler<S> = Box<dyn FnMut(&mut WaylandClient<S>, Box<dyn Any>) + 'static>;
-type WlParserWrapper = dyn Fn(RawMessage) -> Result<Box<dyn Any>>;
+// The state is only missing for the events dispatched while connecting, which can only reach the
+// handlers of the client itself.
+const NO_STATE: &str = "Events dispatched without the application state";
+
+trait EventListener<S>:
+    FnMut(&mut WaylandClient<S>, Option<&mut S>, Box<dyn Any>) -> Option<Box<dyn Any>> + MaybeSend
+{
+}
+impl<S, F> EventListener<S> for F where
+    F: FnMut(&mut WaylandClient<S>, Option<&mut S>, Box<dyn Any>) -> Option<Box<dyn Any>> + MaybeSend
+{
+}
+
+trait EventParser: Fn(RawMessage, Option<UserData>) -> Result<Box<dyn Any>> + MaybeSend {}
+impl<F> EventParser for F where F: Fn(RawMessage, Option<UserData>) -> Result<Box<dyn Any>> + MaybeSend {}
+
+trait CoreInterfaceHandler<S>: FnMut(&mut WaylandClient<S>, &dyn Any) + MaybeSend {}
+impl<S, F> CoreInterfaceHandler<S> for F where F: FnMut(&mut WaylandClient<S>, &dyn Any) + MaybeSend {}
+
+type MockingListener<S> = Box<dyn EventListener<S>>;
+type WlParserWrapper = dyn EventParser;
+type ObjectListeners<S> = Listeners<dyn EventListener<S>>;
 
 struct WlObjectEntry<S> {
     interface: WlObjectInterfaceInfo,
     event_parser: Box<WlParserWrapper>,
-    handler: Option<MockingHandler<S>>,
+    user_data: Option<UserData>,
+    listeners: ListenerList<dyn EventListener<S>, RawMessage>,
+    queue: EventQueueId,
+    // deleted by the server while some of its events were still pending
+    deleted: bool,
 }
 
 struct WlObjectManager<S> {
     objects: HashMap<WaylandId, WlObjectEntry<S>>,
+    // ids acknowledged as deleted by the server (through `wl_display.delete_id`)
+    free_ids: Vec<WaylandId>,
     objects_id_count: u32,
 }
 
-struct WlObjectEntryCopy<'a, S> {
-    interface: WlObjectInterfaceInfo,
-    handler: Option<MockingHandler<S>>,
-    parser: &'a WlParserWrapper,
-}
-
 #[derive(Copy, Clone)]
 struct WlObjectInterfaceInfo {
     id: u32,
     display_name: &'static str,
+    wire_name: &'static str,
+    event_fds: fn(WlEventId) -> usize,
+    error_name: fn(u32) -> Option<&'static str>,
 }
 
 impl<S> WlObjectManager<S> {
     fn new() -> Self {
         Self {
             objects: HashMap::new(),
-            objects_id_count: 0,
+            free_ids: Vec::new(),
+            objects_id_count: CLIENT_MIN_ID - 1,
         }
     }
 
@@ -361,67 +906,136 @@ impl<S> WlObjectManager<S> {
         &mut self,
         interface: WlObjectInterfaceInfo,
         event_parser: Box<WlParserWrapper>,
+        user_data: Option<UserData>,
     ) -> WaylandId {
-        self.objects_id_count += 1;
-        assert!(self.objects_id_count < WaylandId::MAX); // FIXME: use the right upper bound
+        let object_id = self.free_ids.pop().unwrap_or_else(|| {
+            assert!(
+                self.objects_id_count < CLIENT_MAX_ID,
+                "All the client object ids are in use"
+            );
+            self.objects_id_count += 1;
+            self.objects_id_count
+        });
+
         assert!(self
             .objects
             .insert(
-                self.objects_id_count,
+                object_id,
                 WlObjectEntry {
                     interface,
                     event_parser,
-                    handler: None
+                    user_data,
+                    listeners: ListenerList::new(),
+                    queue: DEFAULT_QUEUE_ID,
+                    deleted: false,
                 }
             )
             .is_none());
-        self.objects_id_count
+        object_id
+    }
+
+    fn new_proxy<T: WlInterface<Event = E>, E: Sized + 'static>(
+        &mut self,
+        stream: StreamRef,
+        user_data: Option<UserData>,
+    ) -> T {
+        let object_id = self.new_object(
+            WlObjectInterfaceInfo {
+                id: T::get_interface_id(),
+                display_name: T::get_display_name(),
+                wire_name: T::get_interface_name(),
+                event_fds: T::get_event_fds,
+                error_name: T::get_error_name,
+            },
+            Box::new(|raw_msg, user_data| {
+                let mut msg = T::parse_msg(raw_msg)?;
+                msg.user_data = user_data;
+                Ok(msg.to_any())
+            }),
+            user_data.clone(),
+        );
+
+        T::build(object_id, stream, user_data)
     }
 
-    // If it returns an error, it will be: WlHandlerRegistryError::NoSuchObject
-    fn remove_handler(&mut self, object_id: WaylandId) -> Result<()> {
+    fn get_reference<T: WlInterface<Event = E>, E>(
+        &self,
+        object_id: WaylandId,
+        stream: StreamRef,
+    ) -> Option<T> {
+        match self.objects.get(&object_id) {
+            Some(entry) if entry.interface.id == T::get_interface_id() => {
+                Some(T::build(object_id, stream, entry.user_data.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn remove_listeners(&mut self, object_id: WaylandId) -> Result<()> {
         let entry = self.objects.get_mut(&object_id).ok_or(Error::NoSuchObject)?;
-        entry.handler = None;
+        entry.listeners.clear();
         Ok(())
     }
 
-    fn add_handler(
+    fn remove_listener(&mut self, object_id: WaylandId, listener_id: u32) -> Result<()> {
+        let entry = self.objects.get_mut(&object_id).ok_or(Error::NoSuchObject)?;
+        entry.listeners.remove(listener_id)
+    }
+
+    fn add_listener(
         &mut self,
         object_id: WaylandId,
         interface_id: WlInterfaceId,
-        handler: MockingHandler<S>,
-    ) -> Result<()> {
+        listener: MockingListener<S>,
+    ) -> Result<u32> {
         let entry = self.objects.get_mut(&object_id).ok_or(Error::NoSuchObject)?;
 
         if entry.interface.id != interface_id {
-            Err(Error::InvalidInterface)
-        } else if entry.handler.is_some() {
-            Err(Error::HandlerAlreadlyInPlace)
-        } else {
-            entry.handler = Some(handler);
-            Ok(())
+            return Err(Error::InvalidInterface);
         }
+
+        Ok(entry.listeners.add(listener))
     }
 
-    fn get_object_interface(&self, object_id: WaylandId) -> Option<WlInterfaceId> {
-        self.objects.get(&object_id).map(|e| e.interface.id)
+    fn get_object_interface_info(&self, object_id: WaylandId) -> Option<WlObjectInterfaceInfo> {
+        self.objects.get(&object_id).map(|e| e.interface)
     }
 
-    fn get_object_entry_copy(
-        &mut self,
-        object_id: WaylandId,
-    ) -> Option<WlObjectEntryCopy<'_, S>> {
-        let entry = self.objects.get_mut(&object_id)?;
+    fn get_event_fds(&self, object_id: WaylandId, event_id: WlEventId) -> usize {
+        self.objects
+            .get(&object_id)
+            .map_or(0, |e| (e.interface.event_fds)(event_id))
+    }
 
-        Some(WlObjectEntryCopy {
-            handler: entry.handler.take(),
-            parser: entry.event_parser.as_ref(),
-            interface: entry.interface,
-        })
+    fn get_object_queue(&self, object_id: WaylandId) -> Option<EventQueueId> {
+        self.objects.get(&object_id).map(|e| e.queue)
+    }
+
+    fn set_object_queue(&mut self, object_id: WaylandId, queue: EventQueueId) -> Result<()> {
+        let entry = self.objects.get_mut(&object_id).ok_or(Error::NoSuchObject)?;
+        entry.queue = queue;
+        Ok(())
+    }
+
+    fn reassign_queue(&mut self, from: EventQueueId, to: EventQueueId) {
+        self.objects
+            .values_mut()
+            .filter(|entry| entry.queue == from)
+            .for_each(|entry| entry.queue = to);
     }
 
-    fn remove_object(&mut self, object_id: WaylandId) {
-        self.objects.remove(&object_id);
+    fn get_object_entry_mut(&mut self, object_id: WaylandId) -> Option<&mut WlObjectEntry<S>> {
+        self.objects.get_mut(&object_id)
+    }
+
+    // Removes the object and makes its id available to new objects, which should only be done
+    // once the server acknowledges it deleted the object.
+    fn remove_object(&mut self, object_id: WaylandId) -> Option<WlObjectEntry<S>> {
+        let entry = self.objects.remove(&object_id)?;
+        if (CLIENT_MIN_ID..=CLIENT_MAX_ID).contains(&object_id) {
+            self.free_ids.push(object_id);
+        }
+        Some(entry)
     }
 }
 
@@ -430,17 +1044,28 @@ pub struct ByteBuffer {
     data: Box<[u8]>,
     head: usize,
     tail: usize,
+    fds: VecDeque<OwnedFd>,
 }
 
 impl ByteBuffer {
+    // Enough space for the maximum number of file descriptors libwayland sends at once (28)
+    const ANCILLARY_SIZE: usize = 256;
+
     pub fn new(size: usize) -> Self {
         ByteBuffer {
             head: 0,
             tail: 0,
             data: vec![0; size].into_boxed_slice(),
+            fds: VecDeque::new(),
         }
     }
 
+    /// Takes (at most) `count` of the received file descriptors, by the order they were received.
+    pub fn take_fds(&mut self, count: usize) -> Vec<OwnedFd> {
+        let count = usize::min(count, self.fds.len());
+        self.fds.drain(..count).collect()
+    }
+
     fn cached_bytes(&self) -> usize {
         self.tail - self.head
     }
@@ -449,19 +1074,28 @@ impl ByteBuffer {
         self.data.len() - self.tail
     }
 
-    pub fn read_bytes(&mut self, bytes: usize, stream: &mut impl Read) -> Result<&[u8]> {
-        let cached_bytes = self.cached_bytes();
+    /// The first `bytes` cached bytes, they have to be [`Self::fill`]ed first.
+    pub fn peek(&self, bytes: usize) -> &[u8] {
+        &self.data[self.head..self.head + bytes]
+    }
 
-        assert!(
-            bytes < self.data.len() && cached_bytes <= self.data.len(),
-            "bytes = {bytes}, cached = {cached_bytes}, data_size = {}",
-            self.data.len()
-        );
+    /// Like [`Self::peek`], but the bytes are removed from the buffer.
+    pub fn consume(&mut self, bytes: usize) -> &[u8] {
+        let res = &self.data[self.head..self.head + bytes];
+        self.head += bytes;
+        res
+    }
+
+    /// Receives from `stream` until at least `bytes` bytes are cached, waiting at most until
+    /// `deadline` (if any). The cached bytes are kept when it fails.
+    pub fn fill(&mut self, bytes: usize, stream: &UnixStream, deadline: Option<Instant>) -> Result<()> {
+        let cached_bytes = self.cached_bytes();
+        if bytes > self.data.len() {
+            return Err(crate::protocol::Error::InvalidMessageSize(bytes).into());
+        }
 
         if bytes <= cached_bytes {
-            let res = &self.data[self.head..self.head + bytes];
-            self.head += bytes;
-            return Ok(res);
+            return Ok(());
         }
 
         let left_space = self.tail_space();
@@ -471,25 +1105,280 @@ impl ByteBuffer {
             self.tail = cached_bytes;
         }
 
-        let size = stream.read(&mut self.data[self.tail..])?;
+        // the message might arrive in several chunks
+        while self.cached_bytes() < bytes {
+            if let Some(deadline) = deadline {
+                Self::wait_readable(stream, deadline)?;
+            }
+            self.recv(stream)?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_readable(stream: &UnixStream, deadline: Instant) -> Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // rounded up, so that it doesn't spin when less than a millisecond is left
+            let timeout = remaining.as_nanos().div_ceil(1_000_000);
+            let timeout = libc::c_int::try_from(timeout).unwrap_or(libc::c_int::MAX);
+
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+                0 => return Err(Error::Timeout),
+                -1 => {
+                    let error = std::io::Error::last_os_error();
+                    if error.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(crate::protocol::Error::from(error).into());
+                    }
+                }
+                // errors and hang ups are reported by recv
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn recv(&mut self, stream: &UnixStream) -> Result<()> {
+        let mut ancillary_buffer = [0; Self::ANCILLARY_SIZE];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+        let size = match stream.recv_vectored_with_ancillary(
+            &mut [IoSliceMut::new(&mut self.data[self.tail..])],
+            &mut ancillary,
+        ) {
+            Ok(0) => return Err(crate::protocol::Error::Disconnected.into()),
+            Err(err) if is_disconnect(&err) => return Err(crate::protocol::Error::Disconnected.into()),
+            other => other?,
+        };
         self.tail += size;
 
-        let gotten = size + cached_bytes;
-        if gotten == 0 {
-            panic!("Read 0 bytes from stream! Maybe it was closed...")
-            //Err(fatal_error!("Read 0 bytes from stream! Maybe it was closed..."))
-        } else if gotten < bytes {
-            //Err(fallback_error!(
-            //    "Failed to read {bytes} bytes, only able to read {gotten}!"
-            //))
-            panic!("Failed to read {bytes} bytes, only able to read {gotten}!")
-        } else {
-            let res = &self.data[self.head..self.head + bytes];
-            self.head += bytes;
-            Ok(res)
+        for data in ancillary.messages().flatten() {
+            if let AncillaryData::ScmRights(fds) = data {
+                self.fds
+                    .extend(fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
         }
+
+        if ancillary.truncated() {
+            warn!("Some of the received file descriptors were discarded");
+        }
+
+        Ok(())
     }
 }
 
-//#[cfg(test)]
-//mod test {}
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use super::{
+        test_server::{Arg, TestServer},
+        *,
+    };
+
+    const HEADER: [u8; 8] = [1, 0, 0, 0, 0, 0, 12, 0];
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    fn deadline() -> Option<Instant> {
+        Some(Instant::now() + TIMEOUT)
+    }
+
+    #[test]
+    fn partial_reads_are_kept_across_timeouts() {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let mut buffer = ByteBuffer::new(64);
+
+        server.write_all(&HEADER[..5]).unwrap();
+        assert!(matches!(buffer.fill(HEADER.len(), &client, deadline()), Err(Error::Timeout)));
+        assert_eq!(buffer.cached_bytes(), 5);
+
+        server.write_all(&HEADER[5..]).unwrap();
+        server.write_all(&[7, 0, 0, 0]).unwrap();
+        buffer.fill(HEADER.len(), &client, deadline()).unwrap();
+        assert_eq!(buffer.consume(HEADER.len()), HEADER);
+
+        // the rest of the message is still there
+        buffer.fill(4, &client, deadline()).unwrap();
+        assert_eq!(buffer.peek(4), [7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn timeouts_before_a_header_arrives_lose_nothing() {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let mut buffer = ByteBuffer::new(64);
+
+        let start = Instant::now();
+        assert!(matches!(buffer.fill(HEADER.len(), &client, deadline()), Err(Error::Timeout)));
+        assert!(start.elapsed() >= TIMEOUT);
+        assert_eq!(buffer.cached_bytes(), 0);
+
+        assert!(matches!(
+            ByteBuffer::wait_readable(&client, Instant::now() + TIMEOUT),
+            Err(Error::Timeout)
+        ));
+        server.write_all(&HEADER).unwrap();
+        ByteBuffer::wait_readable(&client, Instant::now() + TIMEOUT).unwrap();
+
+        buffer.fill(HEADER.len(), &client, deadline()).unwrap();
+        assert_eq!(buffer.peek(HEADER.len()), HEADER);
+    }
+
+    #[test]
+    fn events_read_by_a_listener_of_their_object_wait_for_it() {
+        // the surface enters two outputs on its first commit
+        let server = TestServer::spawn(&[("wl_compositor", 6)], |connection, request| {
+            if request.opcode == 6 {
+                connection.send(request.object_id, 0, &[Arg::Uint(1)]);
+                connection.send(request.object_id, 0, &[Arg::Uint(2)]);
+            }
+        });
+
+        let mut client = WaylandClient::<Vec<(&str, u32)>>::connect_to(server.path()).unwrap();
+        let compositor: WlCompositor = client.get_global().unwrap();
+        let surface: WlSurface = client.new_object();
+        compositor.create_surface(&surface).unwrap();
+
+        client
+            .add_event_listener(&surface, |entered, client, msg| {
+                let WlSurfaceEvent::Enter { output_id } = msg.event else {
+                    return None;
+                };
+                entered.push(("first", output_id));
+                if output_id == 1 {
+                    // the second event is read by this roundtrip
+                    let surface: WlSurface = client.get_reference(msg.object_id).unwrap();
+                    client
+                        .add_event_handler(&surface, |entered, _, msg| {
+                            if let WlSurfaceEvent::Enter { output_id } = msg.event {
+                                entered.push(("added", output_id));
+                            }
+                        })
+                        .unwrap();
+                    client.roundtrip(entered).unwrap();
+                    return None;
+                }
+                Some(msg)
+            })
+            .unwrap();
+
+        let mut entered = Vec::new();
+        surface.commit().unwrap();
+        client.roundtrip(&mut entered).unwrap();
+        assert_eq!(entered, [("first", 1), ("first", 2), ("added", 2)]);
+
+        // the proxies keep the connection open as well
+        drop((client, compositor, surface));
+        server.join();
+    }
+
+    #[test]
+    fn deleted_objects_are_kept_until_their_queued_events_are_dispatched() {
+        let server = TestServer::spawn(&[], |_, _| ());
+        let mut client = WaylandClient::<bool>::connect_to(server.path()).unwrap();
+        let display: WlDisplay = client.get_global().unwrap();
+
+        let queue = client.new_event_queue();
+        let callback: WlCallBack = client.new_object_in_queue(queue).unwrap();
+        let callback_id = callback.get_object_id();
+        client.add_event_handler(&callback, |done, _, _| *done = true).unwrap();
+        display.sync(&callback).unwrap();
+
+        // reads the event of the callback and its deletion, which is dispatched right away
+        let mut done = false;
+        client.roundtrip(&mut done).unwrap();
+        assert!(!done);
+        let other: WlCallBack = client.new_object();
+        assert_ne!(other.get_object_id(), callback_id);
+
+        client.dispatch_queue_pending(queue, &mut done).unwrap();
+        assert!(done);
+        assert!(client.get_reference::<WlCallBack, _>(callback_id).is_none());
+
+        drop((client, display, callback, other));
+        server.join();
+    }
+
+    #[test]
+    fn fallbacks_are_not_replaced_while_they_run() {
+        // every surface enters an output whenever one of them is committed
+        let (mut compositor, mut surfaces) = (0, Vec::new());
+        let server = TestServer::spawn(&[("wl_compositor", 6)], move |connection, request| {
+            match request.opcode {
+                0 if request.object_id == compositor => surfaces.push(request.args[0]),
+                // the bind of the compositor, whose id is the last argument
+                0 => compositor = *request.args.last().unwrap(),
+                6 => surfaces.iter().for_each(|id| connection.send(*id, 0, &[Arg::Uint(1)])),
+                _ => (),
+            }
+        });
+
+        let mut client = WaylandClient::<u32>::connect_to(server.path()).unwrap();
+        let compositor: WlCompositor = client.get_global().unwrap();
+        let (first, second): (WlSurface, WlSurface) = (client.new_object(), client.new_object());
+        compositor.create_surface(&first).unwrap();
+        compositor.create_surface(&second).unwrap();
+
+        client.set_unhandled_policy(UnhandledPolicy::fallback(|calls: &mut u32, client, _| {
+            *calls += 1;
+            // the event of the second surface comes while the fallback is running
+            client.roundtrip(calls).unwrap();
+            client.set_unhandled_policy(UnhandledPolicy::Error);
+        }));
+
+        let mut calls = 0;
+        first.commit().unwrap();
+        client.roundtrip(&mut calls).unwrap();
+        assert_eq!(calls, 1);
+
+        // the policy set by the fallback is kept
+        first.commit().unwrap();
+        assert!(client.roundtrip(&mut calls).is_err());
+        assert_eq!(calls, 1);
+
+        drop((client, compositor, first, second));
+        server.join();
+    }
+
+    #[test]
+    fn messages_as_big_as_the_buffer_fit() {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let mut buffer = ByteBuffer::new(16);
+
+        server.write_all(&[1; 4]).unwrap();
+        buffer.fill(4, &client, deadline()).unwrap();
+        buffer.consume(4);
+
+        // the message doesn't fit after what was consumed
+        server.write_all(&[2; 16]).unwrap();
+        buffer.fill(16, &client, deadline()).unwrap();
+        assert_eq!(buffer.consume(16), [2; 16]);
+
+        let error = buffer.fill(17, &client, deadline()).unwrap_err();
+        assert!(error.is_fatal());
+    }
+
+    #[test]
+    fn messages_shorter_than_their_header_are_fatal() {
+        // answers `create_surface`, after the compositor was bound through the registry (2)
+        let server = TestServer::spawn(&[("wl_compositor", 6)], |connection, request| {
+            if request.object_id != 2 {
+                connection.send_raw(&[1, 0, 0, 0, 0, 0, 4, 0], &[]);
+            }
+        });
+
+        let mut client = WaylandClient::<()>::connect_to(server.path()).unwrap();
+        let compositor: WlCompositor = client.get_global().unwrap();
+        let surface: WlSurface = client.new_object();
+        compositor.create_surface(&surface).unwrap();
+
+        let error = client.roundtrip(&mut ()).unwrap_err();
+        assert!(error.is_fatal(), "{error:?}");
+
+        drop((client, compositor, surface));
+        server.join();
+    }
+}
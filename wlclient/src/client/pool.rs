@@ -0,0 +1,392 @@
+use std::{fs::File, ops::Range, os::fd::AsRawFd};
+
+use log::warn;
+use memmap::MmapOptions;
+
+use super::{memory::{self, PixelView, SharedBuffer}, WaylandClient};
+use crate::{
+    error::Result,
+    protocol::{base::*, WlInterface},
+    sync::{Lock, Shared},
+};
+
+/// A `wl_shm_pool` that hands out buffers of any size and format from its free space, growing
+/// when it runs out of it.
+///
+/// The memory of a buffer is reclaimed once its [`ShmBuffer`] is dropped and the compositor
+/// released it.
+pub struct ShmPool {
+    pool: WlShmPool,
+    file: File,
+    memory: SharedBuffer,
+    slots: Shared<Lock<Slots>>,
+}
+
+/// A `wl_buffer` allocated from a [`ShmPool`].
+///
+/// Its release events are consumed by the pool, see [`ShmBuffer::is_busy`].
+pub struct ShmBuffer {
+    buffer: WlBuffer,
+    offset: usize,
+    len: usize,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: WlShmFormat,
+    state: Shared<Lock<BufferState>>,
+    slots: Shared<Lock<Slots>>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    // attached to a surface and not released yet
+    busy: bool,
+    // the ShmBuffer was dropped while busy, the slot is freed on release
+    dropped: bool,
+}
+
+// Free ranges of the pool, sorted and coalesced.
+struct Slots {
+    free: Vec<Range<usize>>,
+    size: usize,
+}
+
+impl Slots {
+    fn new(size: usize) -> Self {
+        let mut slots = Slots { free: Vec::new(), size: 0 };
+        slots.grow(size);
+        slots
+    }
+
+    // first fit
+    fn allocate(&mut self, len: usize) -> Option<usize> {
+        let index = self.free.iter().position(|range| range.len() >= len)?;
+        let range = &mut self.free[index];
+        let offset = range.start;
+
+        range.start += len;
+        if range.start == range.end {
+            self.free.remove(index);
+        }
+
+        Some(offset)
+    }
+
+    fn free(&mut self, range: Range<usize>) {
+        let index = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(index, range);
+
+        // merge with the next range, then with the previous one
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+
+    fn grow(&mut self, size: usize) {
+        let old_size = std::mem::replace(&mut self.size, size);
+        self.free(old_size..size);
+    }
+}
+
+impl ShmPool {
+    // offsets of the buffers are kept aligned to cache lines
+    pub(super) const ALIGNMENT: usize = 64;
+
+    pub fn wl_pool(&self) -> &WlShmPool {
+        &self.pool
+    }
+
+    pub fn size(&self) -> usize {
+        self.slots.lock().size
+    }
+
+    /// Creates a `width` x `height` buffer with lines of `stride` bytes, growing the pool if
+    /// there is no free space left for it. The lines must be long enough for `width` pixels of
+    /// `format`.
+    pub fn create_buffer<S>(
+        &mut self,
+        client: &mut WaylandClient<S>,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: WlShmFormat,
+    ) -> Result<ShmBuffer> {
+        // the lines of the formats with several planes can't be checked
+        let short_lines = format.bits_per_pixel().is_some()
+            && format.stride(width).is_none_or(|line| stride < line);
+        if width <= 0 || height <= 0 || stride <= 0 || short_lines {
+            return Err(memory::Error::InvalidBufferSize { width, height, stride }.into());
+        }
+
+        let len = (stride as usize * height as usize).next_multiple_of(Self::ALIGNMENT);
+        let allocated = self.slots.lock().allocate(len);
+        let offset = match allocated {
+            Some(offset) => offset,
+            None => {
+                self.grow(len)?;
+                self.slots
+                    .lock()
+                    .allocate(len)
+                    .expect("The pool was grown to fit the buffer")
+            }
+        };
+
+        let buffer: WlBuffer = client.new_object();
+        let created = self.pool.create_buffer(&buffer, offset as i32, width, height, stride, format);
+        if let Err(error) = created {
+            self.slots.lock().free(offset..offset + len);
+            return Err(error.into());
+        }
+
+        let state = Shared::new(Lock::new(BufferState::default()));
+        {
+            let state = Shared::clone(&state);
+            let slots = Shared::clone(&self.slots);
+            let released = buffer.clone();
+            client.add_core_handler(&buffer, move |_, _, msg| {
+                let WlBufferEvent::Release = msg.event;
+                let mut state = state.lock();
+                state.busy = false;
+                if state.dropped {
+                    free_buffer(&released, &slots, offset..offset + len);
+                }
+            })?;
+        }
+
+        Ok(ShmBuffer {
+            buffer,
+            offset,
+            len,
+            width,
+            height,
+            stride,
+            format,
+            state,
+            slots: Shared::clone(&self.slots),
+        })
+    }
+
+    /// The memory of `buffer`, which has to be allocated from this pool.
+    pub fn data(&mut self, buffer: &ShmBuffer) -> &mut [u8] {
+        assert!(Shared::ptr_eq(&self.slots, &buffer.slots), "The buffer is from another pool");
+        let size = buffer.stride as usize * buffer.height as usize;
+        &mut self.memory[buffer.offset..buffer.offset + size]
+    }
+
+    /// The pixels of `buffer`, see [`Self::data`].
+    pub fn view(&mut self, buffer: &ShmBuffer) -> Result<PixelView<'_>> {
+        let (width, height) = (buffer.width as u32, buffer.height as u32);
+        let stride = buffer.stride as usize;
+        Ok(PixelView::new(self.data(buffer), buffer.format, width, height, stride, 0)?)
+    }
+
+    // Grows the pool so that there is (at least) `len` more bytes, the memory is remapped since
+    // the old mapping doesn't cover the new space.
+    fn grow(&mut self, len: usize) -> Result<()> {
+        let old_size = self.size();
+        let size = usize::max(2 * old_size, old_size + len);
+        let wire_size = i32::try_from(size).map_err(|_| memory::Error::TooLarge(size))?;
+
+        self.file
+            .set_len(size as u64)
+            .map_err(memory::Error::Resize)?;
+        self.pool.resize(wire_size)?;
+
+        let data = unsafe { MmapOptions::new().map_mut(&self.file).map_err(memory::Error::Map)? };
+        self.memory = SharedBuffer(data);
+        self.slots.lock().grow(size);
+
+        Ok(())
+    }
+}
+
+impl Drop for ShmPool {
+    // the buffers keep the memory alive on the server side
+    fn drop(&mut self) {
+        if let Err(error) = self.pool.destroy() {
+            warn!("Failed to destroy wl_shm_pool@{}: {error:?}", self.pool.get_object_id());
+        }
+    }
+}
+
+impl ShmBuffer {
+    pub fn wl_buffer(&self) -> &WlBuffer {
+        &self.buffer
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> i32 {
+        self.stride
+    }
+
+    pub fn format(&self) -> WlShmFormat {
+        self.format
+    }
+
+    /// Whether the compositor may still be reading the buffer.
+    pub fn is_busy(&self) -> bool {
+        self.state.lock().busy
+    }
+
+    /// Attaches the buffer to `surface`, it is busy until the compositor releases it.
+    pub fn attach(&self, surface: &WlSurface, x: i32, y: i32) -> Result<()> {
+        surface.attach(&self.buffer, x, y)?;
+        self.state.lock().busy = true;
+        Ok(())
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if state.busy {
+            state.dropped = true;
+        } else {
+            free_buffer(&self.buffer, &self.slots, self.offset..self.offset + self.len);
+        }
+    }
+}
+
+fn free_buffer(buffer: &WlBuffer, slots: &Lock<Slots>, range: Range<usize>) {
+    if let Err(error) = buffer.destroy() {
+        warn!("Failed to destroy wl_buffer@{}: {error:?}", buffer.get_object_id());
+    }
+    slots.lock().free(range);
+}
+
+impl<S> WaylandClient<S> {
+    /// Whether the compositor supports buffers of `format`.
+    pub fn supports(&self, format: WlShmFormat) -> bool {
+        matches!(format, WlShmFormat::Argb8888 | WlShmFormat::Xrgb8888)
+            || self.shm_formats.contains(&(format as u32))
+    }
+
+    /// The formats advertised by the compositor (which might include formats unknown to
+    /// [`WlShmFormat`]).
+    pub fn shm_formats(&self) -> impl Iterator<Item = u32> + '_ {
+        self.shm_formats.iter().copied()
+    }
+
+    /// Creates a [`ShmPool`] of (initially) `size` bytes.
+    pub fn create_shm_pool(&mut self, size: usize) -> Result<ShmPool> {
+        let wire_size = i32::try_from(size).map_err(|_| memory::Error::TooLarge(size))?;
+        assert!(wire_size > 0);
+
+        let shm: WlShm = self.get_global().expect("Failed to get global WlShm");
+        let pool: WlShmPool = self.new_object();
+        let (memory, file) = SharedBuffer::alloc(size)?;
+
+        shm.create_pool(&pool, file.as_raw_fd(), wire_size)?;
+
+        Ok(ShmPool {
+            pool,
+            file,
+            memory,
+            slots: Shared::new(Lock::new(Slots::new(size))),
+        })
+    }
+}
+
+#[cfg(test)]
+// the free ranges are compared with arrays of ranges
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::{super::test_server::TestServer, *};
+    use crate::error::Error;
+
+    #[test]
+    fn allocations_are_first_fit() {
+        let mut slots = Slots::new(100);
+        assert_eq!(slots.allocate(30), Some(0));
+        assert_eq!(slots.allocate(30), Some(30));
+        assert_eq!(slots.free, [60..100]);
+
+        assert_eq!(slots.allocate(40), Some(60));
+        assert!(slots.free.is_empty());
+        assert_eq!(slots.allocate(1), None);
+    }
+
+    #[test]
+    fn freed_slots_are_coalesced_on_both_sides() {
+        let mut slots = Slots::new(100);
+        for _ in 0..4 {
+            slots.allocate(25).unwrap();
+        }
+
+        // merged with the next range
+        slots.free(50..75);
+        slots.free(25..50);
+        assert_eq!(slots.free, [25..75]);
+
+        // merged with the previous range
+        slots.free(75..100);
+        assert_eq!(slots.free, [25..100]);
+
+        // merged with both
+        assert_eq!(slots.allocate(75), Some(25));
+        slots.free(0..25);
+        slots.free(50..100);
+        assert_eq!(slots.free, [0..25, 50..100]);
+        slots.free(25..50);
+        assert_eq!(slots.free, [0..100]);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut slots = Slots::new(100);
+        let first = slots.allocate(40).unwrap();
+        slots.allocate(40).unwrap();
+
+        slots.free(first..first + 40);
+        assert_eq!(slots.allocate(30), Some(first));
+        assert_eq!(slots.free, [30..40, 80..100]);
+    }
+
+    #[test]
+    fn growing_extends_the_last_free_range() {
+        let mut slots = Slots::new(100);
+        slots.allocate(80).unwrap();
+        assert_eq!(slots.allocate(50), None);
+
+        slots.grow(200);
+        assert_eq!(slots.size, 200);
+        assert_eq!(slots.free, [80..200]);
+        assert_eq!(slots.allocate(50), Some(80));
+
+        // nothing is left to merge with
+        slots.grow(300);
+        assert_eq!(slots.free, [130..300]);
+    }
+
+    #[test]
+    fn invalid_buffer_sizes_are_errors() {
+        let server = TestServer::spawn(&[("wl_shm", 1)], |_, _| ());
+        let mut client = WaylandClient::<()>::connect_to(server.path()).unwrap();
+        let mut pool = client.create_shm_pool(4096).unwrap();
+
+        let format = WlShmFormat::Xrgb8888;
+        for (width, height, stride) in [(0, 0, 0), (10, 0, 40), (10, 10, -40), (10, 10, 39)] {
+            let created = pool.create_buffer(&mut client, width, height, stride, format);
+            assert!(matches!(
+                created,
+                Err(Error::Memory(memory::Error::InvalidBufferSize { .. }))
+            ));
+        }
+
+        // padded lines are fine
+        assert!(pool.create_buffer(&mut client, 10, 10, 48, format).is_ok());
+
+        drop((client, pool));
+        server.join();
+    }
+}
@@ -0,0 +1,178 @@
+use super::{damage::Rect, WaylandClient};
+use crate::{
+    error::Result,
+    protocol::base::{WlCompositor, WlRegion},
+};
+
+/// A set of pixels made of rectangles, e.g. the opaque or the input region of a surface.
+///
+/// ```ignore
+/// // only the title bar of the overlay receives input
+/// let mut input = Region::from(Rect::new(0, 0, width, height));
+/// input.subtract(Rect::new(0, 30, width, height - 30));
+///
+/// let region = client.create_region(&input)?;
+/// surface.set_input_region(Some(&region))?;
+/// region.destroy()?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    // they never overlap
+    rects: Vec<Rect>,
+}
+
+impl Region {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rectangles of the region, which don't overlap.
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.rects.iter().any(|rect| rect.contains(&Rect::new(x, y, 1, 1)))
+    }
+
+    /// The smallest rectangle containing the whole region.
+    pub fn extents(&self) -> Option<Rect> {
+        self.rects.iter().copied().reduce(|extents, rect| extents.bounding_box(&rect))
+    }
+
+    pub fn add(&mut self, rect: Rect) -> &mut Self {
+        // only the parts not covered yet are added
+        let mut pieces = vec![rect];
+        for existing in &self.rects {
+            pieces = pieces.iter().flat_map(|piece| piece.subtract(existing)).collect();
+        }
+        self.rects.extend(pieces);
+        self
+    }
+
+    pub fn subtract(&mut self, rect: Rect) -> &mut Self {
+        self.rects = self.rects.iter().flat_map(|existing| existing.subtract(&rect)).collect();
+        self
+    }
+
+    pub fn union(&mut self, other: &Region) -> &mut Self {
+        other.rects.iter().for_each(|rect| {
+            self.add(*rect);
+        });
+        self
+    }
+
+    pub fn difference(&mut self, other: &Region) -> &mut Self {
+        other.rects.iter().for_each(|rect| {
+            self.subtract(*rect);
+        });
+        self
+    }
+}
+
+impl From<Rect> for Region {
+    fn from(rect: Rect) -> Self {
+        let mut region = Region::new();
+        region.add(rect);
+        region
+    }
+}
+
+impl FromIterator<Rect> for Region {
+    fn from_iter<T: IntoIterator<Item = Rect>>(iter: T) -> Self {
+        let mut region = Region::new();
+        iter.into_iter().for_each(|rect| {
+            region.add(rect);
+        });
+        region
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Creates a `wl_region` with the rectangles of `region`. The surfaces copy the regions
+    /// they are given, so it can be destroyed right after being used.
+    pub fn create_region(&mut self, region: &Region) -> Result<WlRegion> {
+        let compositor: WlCompositor = self.get_global().expect("Failed to get global WlCompositor");
+        let wl_region: WlRegion = self.new_object();
+        compositor.create_region(&wl_region)?;
+
+        for rect in region.rects() {
+            wl_region.add(rect.x, rect.y, rect.width, rect.height)?;
+        }
+
+        Ok(wl_region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(region: &Region) -> i64 {
+        region.rects().iter().map(Rect::area).sum()
+    }
+
+    fn assert_disjoint(region: &Region) {
+        for (i, a) in region.rects().iter().enumerate() {
+            for b in &region.rects()[i + 1..] {
+                assert!(!a.intersects(b), "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_rects_are_only_counted_once() {
+        let region: Region = [Rect::new(0, 0, 10, 10), Rect::new(5, 5, 10, 10)].into_iter().collect();
+
+        assert_disjoint(&region);
+        assert_eq!(area(&region), 175);
+        assert_eq!(region.extents(), Some(Rect::new(0, 0, 15, 15)));
+        assert!(region.contains(7, 7));
+        assert!(region.contains(12, 12));
+        assert!(!region.contains(12, 2));
+
+        // a rect that is already covered adds nothing
+        let mut covered = region.clone();
+        covered.add(Rect::new(2, 2, 5, 5));
+        assert_eq!(covered, region);
+    }
+
+    #[test]
+    fn subtracting_everything_leaves_an_empty_region() {
+        let mut region: Region = [Rect::new(0, 0, 10, 10), Rect::new(20, 0, 10, 10)].into_iter().collect();
+
+        region.subtract(Rect::new(2, 2, 4, 4));
+        assert_disjoint(&region);
+        assert_eq!(area(&region), 184);
+        assert!(!region.contains(3, 3));
+
+        region.subtract(Rect::new(-5, -5, 40, 20));
+        assert!(region.is_empty());
+        assert_eq!(region.extents(), None);
+
+        // so does the difference with a covering region
+        let mut region = Region::from(Rect::new(0, 0, 10, 10));
+        region.difference(&Region::from(Rect::new(0, 0, 10, 10)));
+        assert!(region.is_empty());
+    }
+
+    #[test]
+    fn disjoint_unions_keep_every_rect() {
+        let left = Region::from(Rect::new(0, 0, 10, 10));
+        let right = Region::from(Rect::new(30, 0, 10, 10));
+
+        let mut union = left.clone();
+        union.union(&right);
+        assert_eq!(union.rects(), [Rect::new(0, 0, 10, 10), Rect::new(30, 0, 10, 10)]);
+        assert_eq!(union.extents(), Some(Rect::new(0, 0, 40, 10)));
+        assert!(!union.contains(20, 5));
+
+        // and taking one back out leaves the other
+        union.difference(&left);
+        assert_eq!(union, right);
+    }
+}
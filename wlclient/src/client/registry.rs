@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+};
+
+use super::{listeners::ListenerList, WaylandClient};
+use crate::{
+    error::{Error, Result},
+    protocol::{base::WlRegistry, WaylandId, WlInterface},
+    sync::MaybeSend,
+};
+
+/// A global advertised by the server through `wl_registry.global`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalInfo {
+    /// Numeric name of the global, used to bind it.
+    pub name: u32,
+    pub interface: String,
+    pub version: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum GlobalEvent {
+    Added(GlobalInfo),
+    Removed(GlobalInfo),
+}
+
+pub trait GlobalListener<S>: FnMut(&mut S, &mut WaylandClient<S>, &GlobalEvent) + MaybeSend {}
+impl<S, F> GlobalListener<S> for F where
+    F: FnMut(&mut S, &mut WaylandClient<S>, &GlobalEvent) + MaybeSend
+{
+}
+
+/// Identifies a listener added with [`WaylandClient::add_global_listener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalListenerId(u32);
+
+pub(super) struct GlobalRegistry<S> {
+    // by the order they were advertised
+    globals: Vec<GlobalInfo>,
+    // objects bound to each global (by its name)
+    bound: HashMap<u32, Vec<WaylandId>>,
+    // version each of those objects was bound with
+    versions: HashMap<WaylandId, u32>,
+    listeners: ListenerList<dyn GlobalListener<S>, GlobalEvent>,
+}
+
+impl<S> GlobalRegistry<S> {
+    pub(super) fn new() -> Self {
+        Self {
+            globals: Vec::new(),
+            bound: HashMap::new(),
+            versions: HashMap::new(),
+            listeners: ListenerList::new(),
+        }
+    }
+
+    // forgets the globals of a previous connection, but keeps the listeners
+    pub(super) fn reset(&mut self) {
+        self.globals.clear();
+        self.bound.clear();
+        self.versions.clear();
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Every global currently advertised by the server.
+    pub fn globals(&self) -> Vec<GlobalInfo> {
+        self.registry.lock().globals.clone()
+    }
+
+    /// The globals of `interface` (e.g. `"wl_output"`) currently advertised by the server.
+    pub fn find_globals(&self, interface: &str) -> Vec<GlobalInfo> {
+        let registry = self.registry.lock();
+        registry
+            .globals
+            .iter()
+            .filter(|global| global.interface == interface)
+            .cloned()
+            .collect()
+    }
+
+    /// Binds the global `name` using the highest version within `versions` that is both
+    /// advertised by the server and implemented by `T`.
+    ///
+    /// The same global can be bound more than once, every instance can be found with
+    /// [`WaylandClient::get_globals`] until the global is removed.
+    pub fn bind<T, E>(&mut self, name: u32, versions: impl RangeBounds<u32>) -> Result<T>
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+    {
+        let advertised = {
+            let registry = self.registry.lock();
+            let global = registry
+                .globals
+                .iter()
+                .find(|g| g.name == name && g.interface == T::get_interface_name())
+                .ok_or(Error::NoSuchGlobal)?;
+            global.version
+        };
+
+        let min = match versions.start_bound() {
+            Bound::Included(v) => *v,
+            Bound::Excluded(v) => v + 1,
+            Bound::Unbounded => 1,
+        };
+        let max = match versions.end_bound() {
+            Bound::Included(v) => *v,
+            Bound::Excluded(v) => v.saturating_sub(1),
+            Bound::Unbounded => u32::MAX,
+        };
+
+        let version = advertised.min(max).min(T::get_max_version());
+        if version < min.max(1) {
+            return Err(Error::UnsupportedVersion {
+                interface: T::get_interface_name(),
+                advertised,
+            });
+        }
+
+        let registry: WlRegistry = self.get_global().expect("Failed to get global WlRegistry");
+        let object: T = self.new_object();
+        let object_id = object.get_object_id();
+        registry.bind(name, T::get_interface_name().to_string(), version, object_id)?;
+
+        log::info!("Bound {} ({name}) with version {version}", T::get_interface_name());
+        {
+            let mut registry = self.registry.lock();
+            registry.bound.entry(name).or_default().push(object_id);
+            registry.versions.insert(object_id, version);
+        }
+        self.globals
+            .entry(T::get_interface_id())
+            .or_default()
+            .push(object_id);
+        Ok(object)
+    }
+
+    /// Binds every advertised global of `T` that isn't bound yet, returning all the bound
+    /// instances of `T`.
+    pub fn bind_all<T, E>(&mut self) -> Result<Vec<T>>
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+    {
+        let unbound: Vec<u32> = {
+            let registry = self.registry.lock();
+            registry
+                .globals
+                .iter()
+                .filter(|g| g.interface == T::get_interface_name())
+                .filter(|g| !registry.bound.contains_key(&g.name))
+                .map(|g| g.name)
+                .collect()
+        };
+
+        for name in unbound {
+            self.bind::<T, E>(name, ..)?;
+        }
+
+        Ok(self.get_globals())
+    }
+
+    // binds the first advertised global of `T` (if any)
+    pub(super) fn bind_first<T, E>(&mut self) -> Result<()>
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+    {
+        match self.find_globals(T::get_interface_name()).first() {
+            Some(global) => self.bind::<T, E>(global.name, ..).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    // the first bound instance of `T` with one of `versions`, binding the first advertised
+    // global of `T` if there is none (for the globals only some applications need)
+    pub(super) fn global_or_bind<T, E>(&mut self, versions: impl RangeBounds<u32>) -> Result<T>
+    where
+        T: WlInterface<Event = E>,
+        E: Sized + 'static,
+    {
+        let bound = {
+            let registry = self.registry.lock();
+            self.globals
+                .get(&T::get_interface_id())
+                .into_iter()
+                .flatten()
+                .find(|id| registry.versions.get(id).is_some_and(|v| versions.contains(v)))
+                .copied()
+        };
+        if let Some(global) = bound.and_then(|id| self.get_reference(id)) {
+            return Ok(global);
+        }
+
+        let global = self
+            .find_globals(T::get_interface_name())
+            .into_iter()
+            .next()
+            .ok_or(Error::NoSuchGlobal)?;
+        self.bind(global.name, versions)
+    }
+
+    pub(super) fn forget_global(&mut self, object_id: WaylandId) {
+        for ids in self.globals.values_mut() {
+            ids.retain(|id| *id != object_id);
+        }
+
+        let mut registry = self.registry.lock();
+        for ids in registry.bound.values_mut() {
+            ids.retain(|id| *id != object_id);
+        }
+        registry.versions.remove(&object_id);
+    }
+
+    /// Calls `listener` every time a global is added or removed. The globals advertised before
+    /// the listener was added aren't replayed, those can be found with [`Self::globals`].
+    pub fn add_global_listener<F: GlobalListener<S> + 'static>(
+        &mut self,
+        listener: F,
+    ) -> GlobalListenerId {
+        GlobalListenerId(self.registry.lock().listeners.add(Box::new(listener)))
+    }
+
+    pub fn remove_global_listener(&mut self, listener: GlobalListenerId) -> Result<()> {
+        self.registry.lock().listeners.remove(listener.0)
+    }
+
+    pub(super) fn global_added(&mut self, global: GlobalInfo, state: Option<&mut S>) {
+        self.registry.lock().globals.push(global.clone());
+        self.notify_global_listeners(GlobalEvent::Added(global), state);
+    }
+
+    pub(super) fn global_removed(&mut self, name: u32, state: Option<&mut S>) {
+        let global = {
+            let mut registry = self.registry.lock();
+            let Some(idx) = registry.globals.iter().position(|g| g.name == name) else {
+                log::error!("Received removal of unknown global {name}");
+                return;
+            };
+            // the objects are left for the application to destroy, but aren't globals anymore
+            for object_id in registry.bound.remove(&name).unwrap_or_default() {
+                registry.versions.remove(&object_id);
+                for ids in self.globals.values_mut() {
+                    ids.retain(|id| *id != object_id);
+                }
+            }
+            registry.globals.remove(idx)
+        };
+
+        log::info!("Global {} ({name}) was removed", global.interface);
+        self.notify_global_listeners(GlobalEvent::Removed(global), state);
+    }
+
+    fn notify_global_listeners(&mut self, event: GlobalEvent, state: Option<&mut S>) {
+        // there can't be any listener while connecting, which is when the state is missing
+        let Some(state) = state else {
+            return;
+        };
+
+        // a notification is already in progress when a listener caused this one
+        if !self.registry.lock().listeners.queue(event) {
+            return;
+        }
+
+        loop {
+            let next = self.registry.lock().listeners.next_event();
+            let Some((event, mut listeners)) = next else {
+                break;
+            };
+            for (_, listener) in listeners.iter_mut() {
+                listener(state, self, &event);
+            }
+            self.registry.lock().listeners.restore(listeners);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use log::warn;
+
+use super::{listeners::ListenerList, WaylandClient};
+use crate::{
+    error::{Error, Result},
+    protocol::{base::*, WlInterface},
+    sync::MaybeSend,
+};
+
+/// A `wl_seat` (a group of input devices used by a single user) and the devices the client
+/// created for its current capabilities.
+#[derive(Clone)]
+pub struct Seat {
+    seat: WlSeat,
+    // of the global, used to bind it
+    global: u32,
+    version: u32,
+    name: Option<String>,
+    capabilities: u32,
+    pointer: Option<WlPointer>,
+    keyboard: Option<WlKeyboard>,
+    touch: Option<WlTouch>,
+}
+
+impl Seat {
+    pub fn wl_seat(&self) -> &WlSeat {
+        &self.seat
+    }
+
+    /// Version the seat was bound with, which its devices share.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Sent by the compositor since version 2 (e.g. `"seat0"`).
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// See [`seat_capabilities`].
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    pub fn pointer(&self) -> Option<&WlPointer> {
+        self.pointer.as_ref()
+    }
+
+    pub fn keyboard(&self) -> Option<&WlKeyboard> {
+        self.keyboard.as_ref()
+    }
+
+    pub fn touch(&self) -> Option<&WlTouch> {
+        self.touch.as_ref()
+    }
+}
+
+/// An input device of a [`Seat`].
+#[derive(Clone)]
+pub enum SeatDevice {
+    Pointer(WlPointer),
+    Keyboard(WlKeyboard),
+    Touch(WlTouch),
+}
+
+#[derive(Clone)]
+pub enum SeatEvent {
+    /// The seat was bound, its capabilities and name are sent right after.
+    Added(WlSeat),
+    Named { seat: WlSeat, name: String },
+    /// The seat gained a capability and the device was created, it is time to add its
+    /// listeners.
+    DeviceAdded { seat: WlSeat, device: SeatDevice },
+    /// The seat lost a capability and the device was released.
+    DeviceRemoved { seat: WlSeat, device: SeatDevice },
+    /// The global was removed, the seat and its devices were released.
+    Removed(WlSeat),
+}
+
+pub trait SeatListener<S>: FnMut(&mut S, &mut WaylandClient<S>, &SeatEvent) + MaybeSend {}
+impl<S, F> SeatListener<S> for F where F: FnMut(&mut S, &mut WaylandClient<S>, &SeatEvent) + MaybeSend {}
+
+/// Identifies a listener added with [`WaylandClient::add_seat_listener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SeatListenerId(u32);
+
+pub(super) struct Seats<S> {
+    seats: Vec<Seat>,
+    listeners: ListenerList<dyn SeatListener<S>, SeatEvent>,
+}
+
+impl<S> Seats<S> {
+    pub(super) fn new() -> Self {
+        Self {
+            seats: Vec::new(),
+            listeners: ListenerList::new(),
+        }
+    }
+
+    // forgets the seats of a previous connection, but keeps the listeners
+    pub(super) fn reset(&mut self) {
+        self.seats.clear();
+    }
+
+    fn get_mut(&mut self, seat: &WlSeat) -> Option<&mut Seat> {
+        self.seats
+            .iter_mut()
+            .find(|s| s.seat.get_object_id() == seat.get_object_id())
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Every seat advertised by the compositor, which are bound as soon as they are advertised.
+    ///
+    /// The devices created while connecting can only be found here (there is no listener yet to
+    /// announce them), those created later are announced with [`SeatEvent::DeviceAdded`]. The
+    /// events of every device of a kind can also be handled at once with a default handler
+    /// (see [`WaylandClient::set_default_dispatch`]). The unhandled events of the devices are
+    /// ignored unless another policy is set for their interfaces.
+    pub fn seats(&self) -> Vec<Seat> {
+        self.seats.lock().seats.clone()
+    }
+
+    /// Calls `listener` every time a seat is added or removed, or its devices change. What
+    /// happened before the listener was added isn't replayed, see [`Self::seats`].
+    pub fn add_seat_listener<F: SeatListener<S> + 'static>(&mut self, listener: F) -> SeatListenerId {
+        SeatListenerId(self.seats.lock().listeners.add(Box::new(listener)))
+    }
+
+    pub fn remove_seat_listener(&mut self, listener: SeatListenerId) -> Result<()> {
+        self.seats.lock().listeners.remove(listener.0)
+    }
+
+    // binds a seat that was just advertised
+    pub(super) fn seat_added(&mut self, global: u32, state: Option<&mut S>) -> Result<()> {
+        let version = self
+            .find_globals(WlSeat::get_interface_name())
+            .iter()
+            .find(|g| g.name == global)
+            .map(|g| g.version.min(WlSeat::get_max_version()))
+            .ok_or(Error::NoSuchGlobal)?;
+        let seat: WlSeat = self.bind(global, ..)?;
+
+        self.add_core_handler(&seat, |client, state, msg| {
+            let seat: WlSeat = client.get_reference(msg.object_id).unwrap();
+            match msg.event {
+                WlSeatEvent::Capabilities { capabilities } => {
+                    client.update_capabilities(&seat, capabilities, state)
+                }
+                WlSeatEvent::Name { name } => {
+                    if let Some(entry) = client.seats.lock().get_mut(&seat) {
+                        entry.name = Some(name.clone());
+                    }
+                    client.notify_seat_listeners(SeatEvent::Named { seat, name }, state);
+                }
+            }
+        })?;
+
+        self.seats.lock().seats.push(Seat {
+            seat: seat.clone(),
+            global,
+            version,
+            name: None,
+            capabilities: 0,
+            pointer: None,
+            keyboard: None,
+            touch: None,
+        });
+        self.notify_seat_listeners(SeatEvent::Added(seat), state);
+        Ok(())
+    }
+
+    // releases the seat of a global that was removed (if it is one)
+    pub(super) fn seat_removed(&mut self, global: u32, mut state: Option<&mut S>) {
+        let seat = {
+            let mut seats = self.seats.lock();
+            let Some(idx) = seats.seats.iter().position(|s| s.global == global) else {
+                return;
+            };
+            seats.seats.remove(idx)
+        };
+
+        let devices = [
+            seat.pointer.clone().map(SeatDevice::Pointer),
+            seat.keyboard.clone().map(SeatDevice::Keyboard),
+            seat.touch.clone().map(SeatDevice::Touch),
+        ];
+        for device in devices.into_iter().flatten() {
+            self.release_device(&seat, &device);
+            let event = SeatEvent::DeviceRemoved { seat: seat.seat.clone(), device };
+            self.notify_seat_listeners(event, state.as_deref_mut());
+        }
+
+        if seat.version >= 5 {
+            if let Err(error) = seat.seat.release() {
+                warn!("Failed to release wl_seat@{}: {error:?}", seat.seat.get_object_id());
+            }
+        }
+        self.notify_seat_listeners(SeatEvent::Removed(seat.seat), state);
+    }
+
+    // creates the devices of the capabilities that were gained and releases those of the
+    // capabilities that were lost
+    fn update_capabilities(&mut self, seat: &WlSeat, capabilities: u32, mut state: Option<&mut S>) {
+        let Some(entry) = self.seats.lock().get_mut(seat).map(|entry| {
+            entry.capabilities = capabilities;
+            entry.clone()
+        }) else {
+            return;
+        };
+
+        let has = |capability| capabilities & capability != 0;
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+
+        match (has(seat_capabilities::POINTER), &entry.pointer) {
+            (true, None) => added.push(SeatDevice::Pointer(self.new_object())),
+            (false, Some(pointer)) => removed.push(SeatDevice::Pointer(pointer.clone())),
+            _ => (),
+        }
+        match (has(seat_capabilities::KEYBOARD), &entry.keyboard) {
+            (true, None) => added.push(SeatDevice::Keyboard(self.new_object())),
+            (false, Some(keyboard)) => removed.push(SeatDevice::Keyboard(keyboard.clone())),
+            _ => (),
+        }
+        match (has(seat_capabilities::TOUCH), &entry.touch) {
+            (true, None) => added.push(SeatDevice::Touch(self.new_object())),
+            (false, Some(touch)) => removed.push(SeatDevice::Touch(touch.clone())),
+            _ => (),
+        }
+
+        for device in removed {
+            self.release_device(&entry, &device);
+            if let Some(entry) = self.seats.lock().get_mut(seat) {
+                match device {
+                    SeatDevice::Pointer(_) => entry.pointer = None,
+                    SeatDevice::Keyboard(_) => entry.keyboard = None,
+                    SeatDevice::Touch(_) => entry.touch = None,
+                }
+            }
+            let event = SeatEvent::DeviceRemoved { seat: seat.clone(), device };
+            self.notify_seat_listeners(event, state.as_deref_mut());
+        }
+
+        for device in added {
+            let created = match &device {
+                SeatDevice::Pointer(pointer) => seat.get_pointer(pointer),
+                SeatDevice::Keyboard(keyboard) => seat.get_keyboard(keyboard),
+                SeatDevice::Touch(touch) => seat.get_touch(touch),
+            };
+            if let Err(error) = created {
+                warn!("Failed to create a device of wl_seat@{}: {error:?}", seat.get_object_id());
+                continue;
+            }
+
+            if let Some(entry) = self.seats.lock().get_mut(seat) {
+                match &device {
+                    SeatDevice::Pointer(pointer) => entry.pointer = Some(pointer.clone()),
+                    SeatDevice::Keyboard(keyboard) => entry.keyboard = Some(keyboard.clone()),
+                    SeatDevice::Touch(touch) => entry.touch = Some(touch.clone()),
+                }
+            }
+            let event = SeatEvent::DeviceAdded { seat: seat.clone(), device };
+            self.notify_seat_listeners(event, state.as_deref_mut());
+        }
+    }
+
+    // the release requests only exist since version 3, before that the devices are left alone
+    fn release_device(&mut self, seat: &Seat, device: &SeatDevice) {
+        if seat.version < 3 {
+            return;
+        }
+
+        let (released, object_id) = match device {
+            SeatDevice::Pointer(pointer) => (pointer.release(), pointer.get_object_id()),
+            SeatDevice::Keyboard(keyboard) => (keyboard.release(), keyboard.get_object_id()),
+            SeatDevice::Touch(touch) => (touch.release(), touch.get_object_id()),
+        };
+        if let Err(error) = released {
+            warn!("Failed to release the device {object_id}: {error:?}");
+        }
+    }
+
+    fn notify_seat_listeners(&mut self, event: SeatEvent, state: Option<&mut S>) {
+        // there can't be any listener while connecting, which is when the state is missing
+        let Some(state) = state else {
+            return;
+        };
+
+        // a notification is already in progress when a listener caused this one
+        if !self.seats.lock().listeners.queue(event) {
+            return;
+        }
+
+        loop {
+            let next = self.seats.lock().listeners.next_event();
+            let Some((event, mut listeners)) = next else {
+                break;
+            };
+            for (_, listener) in listeners.iter_mut() {
+                listener(state, self, &event);
+            }
+            self.seats.lock().listeners.restore(listeners);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use log::warn;
+
+use super::{
+    pool::{ShmBuffer, ShmPool},
+    WaylandClient,
+};
+use crate::{
+    error::{Error, Result},
+    protocol::{
+        base::*,
+        single_pixel_buffer::WpSinglePixelBufferManagerV1,
+        viewporter::{WpViewport, WpViewporter},
+        WlInterface,
+    },
+};
+
+/// A color with premultiplied alpha, whose channels go from 0 to `u32::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rgba {
+    pub r: u32,
+    pub g: u32,
+    pub b: u32,
+    pub a: u32,
+}
+
+impl Rgba {
+    pub const TRANSPARENT: Rgba = Rgba::new(0, 0, 0, 0);
+    pub const BLACK: Rgba = Rgba::new(0, 0, 0, u32::MAX);
+    pub const WHITE: Rgba = Rgba::new(u32::MAX, u32::MAX, u32::MAX, u32::MAX);
+
+    pub const fn new(r: u32, g: u32, b: u32, a: u32) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// From 8 bits channels with a straight (i.e. not premultiplied) alpha, e.g.
+    /// `Rgba::from_rgba8(0, 0, 0, 128)` to dim what is below.
+    pub fn from_rgba8(r: u8, g: u8, b: u8, a: u8) -> Self {
+        // the 8 bits are repeated so that 255 becomes u32::MAX
+        let channel = |c: u8| (c as u32 * a as u32 / 255) * 0x0101_0101;
+        Self::new(channel(r), channel(g), channel(b), a as u32 * 0x0101_0101)
+    }
+
+    // for WlShmFormat::Argb8888, which is premultiplied as well
+    fn to_argb8888(self) -> u32 {
+        (self.a >> 24) << 24 | (self.r >> 24) << 16 | (self.g >> 24) << 8 | self.b >> 24
+    }
+}
+
+/// A 1x1 buffer of a single color, meant to be stretched over a surface with a viewport
+/// (see [`WaylandClient::get_viewport`]) for backgrounds, dim overlays or placeholders.
+///
+/// ```ignore
+/// let background = client.create_solid_buffer(Rgba::from_rgba8(0, 0, 0, 128))?;
+/// let viewport = client.get_viewport(&surface)?;
+///
+/// background.attach(&surface, 0, 0)?;
+/// viewport.set_destination(width, height)?;
+/// surface.damage_buffer(0, 0, i32::MAX, i32::MAX)?;
+/// surface.commit()?;
+/// ```
+///
+/// The `wl_buffer` is destroyed when it is dropped.
+pub struct SolidBuffer {
+    kind: SolidKind,
+    color: Rgba,
+}
+
+enum SolidKind {
+    SinglePixel(WlBuffer),
+    // the buffer is dropped before its pool
+    Shm { buffer: ShmBuffer, _pool: ShmPool },
+}
+
+impl SolidBuffer {
+    pub fn wl_buffer(&self) -> &WlBuffer {
+        match &self.kind {
+            SolidKind::SinglePixel(buffer) => buffer,
+            SolidKind::Shm { buffer, .. } => buffer.wl_buffer(),
+        }
+    }
+
+    pub fn color(&self) -> Rgba {
+        self.color
+    }
+
+    /// Whether it was created with `wp_single_pixel_buffer_manager_v1` rather than with shared
+    /// memory.
+    pub fn is_single_pixel(&self) -> bool {
+        matches!(self.kind, SolidKind::SinglePixel(_))
+    }
+
+    /// Attaches the buffer to `surface`, it can be attached to any number of surfaces.
+    pub fn attach(&self, surface: &WlSurface, x: i32, y: i32) -> Result<()> {
+        match &self.kind {
+            SolidKind::SinglePixel(buffer) => surface.attach(buffer, x, y).map(|_| ())?,
+            SolidKind::Shm { buffer, .. } => buffer.attach(surface, x, y)?,
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SolidBuffer {
+    // the shm buffer is destroyed once released, see `ShmBuffer`
+    fn drop(&mut self) {
+        if let SolidKind::SinglePixel(buffer) = &self.kind {
+            if let Err(error) = buffer.destroy() {
+                warn!("Failed to destroy wl_buffer@{}: {error:?}", buffer.get_object_id());
+            }
+        }
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Creates a [`SolidBuffer`] of `color` with `wp_single_pixel_buffer_manager_v1`, or with a
+    /// tiny shm pool when the compositor doesn't advertise it.
+    pub fn create_solid_buffer(&mut self, color: Rgba) -> Result<SolidBuffer> {
+        let manager = match self.global_or_bind::<WpSinglePixelBufferManagerV1, _>(..) {
+            Ok(manager) => manager,
+            Err(Error::NoSuchGlobal) => return self.create_shm_solid_buffer(color),
+            Err(error) => return Err(error),
+        };
+
+        let buffer: WlBuffer = self.new_object();
+        manager.create_u32_rgba_buffer(&buffer, color.r, color.g, color.b, color.a)?;
+        // there is no memory to reuse, so the releases don't matter
+        self.add_core_handler(&buffer, |_, _, msg| {
+            let WlBufferEvent::Release = msg.event;
+        })?;
+
+        Ok(SolidBuffer { kind: SolidKind::SinglePixel(buffer), color })
+    }
+
+    fn create_shm_solid_buffer(&mut self, color: Rgba) -> Result<SolidBuffer> {
+        let format = WlShmFormat::Argb8888;
+        let stride = format.stride(1).expect("Argb8888 has a fixed size");
+
+        // a single slot, so that the pool doesn't grow
+        let mut pool = self.create_shm_pool((stride as usize).next_multiple_of(ShmPool::ALIGNMENT))?;
+        let buffer = pool.create_buffer(self, 1, 1, stride, format)?;
+        pool.view(&buffer)?.fill(color.to_argb8888());
+
+        Ok(SolidBuffer { kind: SolidKind::Shm { buffer, _pool: pool }, color })
+    }
+
+    /// Creates the `wp_viewport` of `surface`, binding `wp_viewporter` if needed. A surface can
+    /// only have one viewport at a time.
+    pub fn get_viewport(&mut self, surface: &WlSurface) -> Result<WpViewport> {
+        let viewporter: WpViewporter = self.global_or_bind(..)?;
+        let viewport: WpViewport = self.new_object();
+        viewporter.get_viewport(&viewport, surface)?;
+        Ok(viewport)
+    }
+}
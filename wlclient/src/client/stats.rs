@@ -0,0 +1,104 @@
+use std::{collections::HashMap, time::Duration};
+
+use super::{EventQueue, WaylandClient};
+use crate::protocol::{MessageStats, WaylandId};
+
+/// Counters of a connection, see [`WaylandClient::stats`].
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    /// Requests sent, by interface and opcode.
+    pub sent: HashMap<(&'static str, u16), MessageStats>,
+    /// Events received, by interface and opcode.
+    pub received: HashMap<(&'static str, u16), MessageStats>,
+    pub fds_sent: u64,
+    pub fds_received: u64,
+    /// Time spent running the listeners (and unhandled events handlers) of each interface.
+    pub handler_time: HashMap<&'static str, Duration>,
+    pub roundtrips: u64,
+    pub roundtrips_time: Duration,
+    pub last_roundtrip: Option<Duration>,
+}
+
+impl ConnectionStats {
+    pub fn average_roundtrip(&self) -> Option<Duration> {
+        let roundtrips = u32::try_from(self.roundtrips).ok().filter(|n| *n > 0)?;
+        Some(self.roundtrips_time / roundtrips)
+    }
+
+    pub(super) fn record_received(
+        &mut self,
+        interface: &'static str,
+        event_id: u16,
+        bytes: usize,
+        fds: usize,
+    ) {
+        self.received
+            .entry((interface, event_id))
+            .or_default()
+            .record(bytes);
+        self.fds_received += fds as u64;
+    }
+
+    pub(super) fn record_handler(&mut self, interface: &'static str, time: Duration) {
+        *self.handler_time.entry(interface).or_default() += time;
+    }
+
+    pub(super) fn record_roundtrip(&mut self, time: Duration) {
+        self.roundtrips += 1;
+        self.roundtrips_time += time;
+        self.last_roundtrip = Some(time);
+    }
+}
+
+/// An entry of the object table, see [`WaylandClient::objects`].
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub object_id: WaylandId,
+    pub interface: &'static str,
+    pub queue: EventQueue,
+    /// Whether the object has listeners or its interface a default handler.
+    pub has_handler: bool,
+    /// Type name of the data attached to the object (if any).
+    pub user_data_type: Option<&'static str>,
+}
+
+impl<S> WaylandClient<S> {
+    /// The counters of the connection since it was established (or the last reset).
+    pub fn stats(&self) -> ConnectionStats {
+        let (sent, fds_sent) = self.stream.sent_stats();
+        ConnectionStats {
+            sent,
+            fds_sent,
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stream.reset_sent_stats();
+        self.stats = ConnectionStats::default();
+    }
+
+    /// Snapshot of the live objects, by id.
+    pub fn objects(&self) -> Vec<ObjectInfo> {
+        let unhandled = self.unhandled.lock();
+        let core_handlers = self.core_handlers.lock();
+        let objects = self.objects.lock();
+
+        let mut table: Vec<ObjectInfo> = objects
+            .objects
+            .iter()
+            .map(|(object_id, entry)| ObjectInfo {
+                object_id: *object_id,
+                interface: entry.interface.wire_name,
+                queue: EventQueue(entry.queue),
+                has_handler: !entry.listeners.is_empty()
+                    || unhandled.has_default_handler(entry.interface.id)
+                    || core_handlers.contains_key(&entry.interface.id),
+                user_data_type: entry.user_data.as_ref().map(|data| data.type_name()),
+            })
+            .collect();
+
+        table.sort_by_key(|info| info.object_id);
+        table
+    }
+}
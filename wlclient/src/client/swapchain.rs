@@ -0,0 +1,178 @@
+use super::{
+    memory::{self, PixelView},
+    pool::{ShmBuffer, ShmPool},
+    WaylandClient,
+};
+use crate::{
+    error::Result,
+    protocol::base::{WlShmFormat, WlSurface},
+};
+
+/// A set of shm buffers of the same size and format drawn in turns, so that the application
+/// never draws into a buffer the compositor may still be reading.
+///
+/// ```ignore
+/// let mut back = swapchain.acquire(&mut client, &mut state)?;
+/// draw(back.data());
+/// back.attach(&surface, 0, 0)?;
+/// surface.commit()?;
+/// ```
+pub struct Swapchain {
+    pool: ShmPool,
+    buffers: Vec<ShmBuffer>,
+    max_buffers: usize,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: WlShmFormat,
+}
+
+/// A buffer of a [`Swapchain`] that isn't used by the compositor.
+pub struct BackBuffer<'a> {
+    pool: &'a mut ShmPool,
+    buffer: &'a ShmBuffer,
+}
+
+impl Swapchain {
+    /// Creates a swapchain of (at most) `max_buffers` buffers, they are only allocated when all
+    /// the previous ones are busy.
+    ///
+    /// Fails with [`memory::Error::UnsupportedFormat`] for the formats with several planes, and
+    /// with [`memory::Error::TooLarge`] when a line of `width` pixels is too long.
+    pub fn new<S>(
+        client: &mut WaylandClient<S>,
+        width: i32,
+        height: i32,
+        format: WlShmFormat,
+        max_buffers: usize,
+    ) -> Result<Self> {
+        assert!(max_buffers > 0);
+
+        let stride = Self::stride_of(width, format)?;
+        let pool = client.create_shm_pool(Self::pool_size(stride, height))?;
+
+        Ok(Self {
+            pool,
+            buffers: Vec::with_capacity(max_buffers),
+            max_buffers,
+            width,
+            height,
+            stride,
+            format,
+        })
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn format(&self) -> WlShmFormat {
+        self.format
+    }
+
+    pub fn stride(&self) -> i32 {
+        self.stride
+    }
+
+    /// The buffers allocated so far.
+    pub fn buffers(&self) -> &[ShmBuffer] {
+        &self.buffers
+    }
+
+    /// Changes the size of the buffers. The old buffers are freed once the compositor releases
+    /// them, they have to be forgotten by the [`super::damage::DamageTracker`] they were
+    /// committed with (see [`Self::buffers`]).
+    ///
+    /// The pool grows with the buffers, but it is only reallocated to a smaller one when it is
+    /// much bigger than needed, so that going back and forth between sizes is cheap.
+    pub fn resize<S>(&mut self, client: &mut WaylandClient<S>, width: i32, height: i32) -> Result<()> {
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+
+        let stride = Self::stride_of(width, self.format)?;
+        self.buffers.clear();
+        self.width = width;
+        self.height = height;
+        self.stride = stride;
+
+        let pool_size = Self::pool_size(stride, height);
+        if self.pool.size() > Self::SHRINK_FACTOR * pool_size {
+            // the busy buffers of the old pool are destroyed when they are released
+            self.pool = client.create_shm_pool(pool_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a buffer that isn't busy, allocating a new one if all of them are. `None` if
+    /// there are already `max_buffers` buffers and all of them are busy. It fails while the size
+    /// is empty, e.g. before the surface was first configured.
+    pub fn try_acquire<S>(&mut self, client: &mut WaylandClient<S>) -> Result<Option<BackBuffer<'_>>> {
+        let index = match self.buffers.iter().position(|buffer| !buffer.is_busy()) {
+            Some(index) => index,
+            None if self.buffers.len() < self.max_buffers => {
+                let (width, height) = (self.width, self.height);
+                let buffer = self.pool.create_buffer(client, width, height, self.stride, self.format)?;
+                self.buffers.push(buffer);
+                self.buffers.len() - 1
+            }
+            None => return Ok(None),
+        };
+
+        Ok(Some(BackBuffer {
+            pool: &mut self.pool,
+            buffer: &self.buffers[index],
+        }))
+    }
+
+    /// Like [`Self::try_acquire`], but dispatches the events of the default queue until a
+    /// buffer is released when all of them are busy.
+    ///
+    /// It must not be called from an event handler, use [`Self::try_acquire`] instead.
+    pub fn acquire<S>(&mut self, client: &mut WaylandClient<S>, state: &mut S) -> Result<BackBuffer<'_>> {
+        while self.try_acquire(client)?.is_none() {
+            client.dispatch(state)?;
+        }
+
+        Ok(self.try_acquire(client)?.expect("A buffer is available"))
+    }
+
+    // a pool this many times bigger than needed is reallocated
+    const SHRINK_FACTOR: usize = 4;
+
+    // room for double buffering, the pool grows if more is needed
+    fn pool_size(stride: i32, height: i32) -> usize {
+        let buffer_size = stride as usize * height as usize;
+        usize::max(2 * buffer_size, 4096)
+    }
+
+    fn stride_of(width: i32, format: WlShmFormat) -> Result<i32> {
+        let bits = format.bits_per_pixel().ok_or(memory::Error::UnsupportedFormat(format))?;
+        let line_size = || (width.max(0) as usize * bits as usize).div_ceil(8);
+        Ok(format.stride(width).ok_or_else(|| memory::Error::TooLarge(line_size()))?)
+    }
+}
+
+impl BackBuffer<'_> {
+    pub fn buffer(&self) -> &ShmBuffer {
+        self.buffer
+    }
+
+    pub fn data(&mut self) -> &mut [u8] {
+        self.pool.data(self.buffer)
+    }
+
+    pub fn view(&mut self) -> Result<PixelView<'_>> {
+        self.pool.view(self.buffer)
+    }
+
+    /// Attaches the buffer to `surface`, it stays busy until the compositor releases it.
+    pub fn attach(&self, surface: &WlSurface, x: i32, y: i32) -> Result<()> {
+        self.buffer.attach(surface, x, y)
+    }
+}
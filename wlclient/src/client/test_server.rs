@@ -0,0 +1,180 @@
+// A compositor for the tests, at the other end of a socket. It answers the requests every
+// client sends (`wl_display.get_registry` and `wl_display.sync`) and hands the others to the
+// test, which scripts the events to send back.
+
+use std::{
+    collections::VecDeque,
+    env,
+    io::{IoSlice, IoSliceMut},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::net::{AncillaryData, SocketAncillary, UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    thread::{self, JoinHandle},
+};
+
+const WL_DISPLAY_ID: u32 = 1;
+
+pub(super) enum Arg<'a> {
+    Uint(u32),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+    Fd(BorrowedFd<'a>),
+}
+
+// A request of the client, whose arguments are left as 32 bits words.
+pub(super) struct Request {
+    pub object_id: u32,
+    pub opcode: u16,
+    pub args: Vec<u32>,
+}
+
+pub(super) struct Connection {
+    socket: UnixStream,
+    // read but not parsed yet
+    data: VecDeque<u8>,
+}
+
+pub(super) struct TestServer {
+    path: PathBuf,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    // Advertises `globals` (interfaces and versions, their names are their indices plus one)
+    // and calls `handler` for every request but those of the display.
+    pub(super) fn spawn<F>(globals: &[(&'static str, u32)], mut handler: F) -> Self
+    where
+        F: FnMut(&mut Connection, Request) + Send + 'static,
+    {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("wlclient-test-{}-{count}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).expect("Failed to bind the test socket");
+
+        let globals = globals.to_vec();
+        let thread = thread::spawn(move || {
+            let (socket, _) = listener.accept().expect("The client didn't connect");
+            let mut connection = Connection { socket, data: VecDeque::new() };
+
+            while let Some(request) = connection.next_request() {
+                match (request.object_id, request.opcode) {
+                    (WL_DISPLAY_ID, 0) => {
+                        let callback = request.args[0];
+                        connection.send(callback, 0, &[Arg::Uint(0)]);
+                        connection.send(WL_DISPLAY_ID, 1, &[Arg::Uint(callback)]);
+                    }
+                    (WL_DISPLAY_ID, 1) => {
+                        for (name, (interface, version)) in globals.iter().enumerate() {
+                            let name = name as u32 + 1;
+                            let args = [Arg::Uint(name), Arg::Str(interface), Arg::Uint(*version)];
+                            connection.send(request.args[0], 0, &args);
+                        }
+                    }
+                    _ => handler(&mut connection, request),
+                }
+            }
+        });
+
+        Self { path, thread: Some(thread) }
+    }
+
+    pub(super) fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    // Waits for the client to disconnect (once it and all its proxies are dropped), so that the
+    // failed assertions of the server are reported.
+    pub(super) fn join(mut self) {
+        if let Err(panic) = self.thread.take().unwrap().join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Connection {
+    pub(super) fn send(&mut self, object_id: u32, opcode: u16, args: &[Arg<'_>]) {
+        let mut payload = Vec::new();
+        let mut fds = Vec::new();
+        for arg in args {
+            match arg {
+                Arg::Uint(value) => payload.extend(value.to_ne_bytes()),
+                Arg::Str(value) => Self::write_array(&mut payload, &[value.as_bytes(), &[0]].concat()),
+                Arg::Bytes(value) => Self::write_array(&mut payload, value),
+                // sent along with the message
+                Arg::Fd(fd) => fds.push(fd.as_raw_fd()),
+            }
+        }
+
+        let size = (8 + payload.len()) as u32;
+        let mut msg = object_id.to_ne_bytes().to_vec();
+        msg.extend((size << 16 | opcode as u32).to_ne_bytes());
+        msg.extend(payload);
+        self.send_raw(&msg, &fds);
+    }
+
+    // for the malformed messages
+    pub(super) fn send_raw(&mut self, bytes: &[u8], fds: &[i32]) {
+        let mut ancillary_buffer = [0; 64];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+        assert!(ancillary.add_fds(fds));
+        self.socket
+            .send_vectored_with_ancillary(&[IoSlice::new(bytes)], &mut ancillary)
+            .expect("Failed to send an event");
+    }
+
+    fn write_array(payload: &mut Vec<u8>, bytes: &[u8]) {
+        payload.extend((bytes.len() as u32).to_ne_bytes());
+        payload.extend(bytes);
+        payload.resize(payload.len().next_multiple_of(4), 0);
+    }
+
+    // `None` once the client disconnected
+    fn next_request(&mut self) -> Option<Request> {
+        let header = self.read(8)?;
+        let object_id = u32::from_ne_bytes(header[..4].try_into().unwrap());
+        let size_and_opcode = u32::from_ne_bytes(header[4..].try_into().unwrap());
+
+        let payload = self.read((size_and_opcode >> 16) as usize - 8)?;
+        let args = payload
+            .chunks_exact(4)
+            .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        Some(Request { object_id, opcode: size_and_opcode as u16, args })
+    }
+
+    // the file descriptors sent by the client are closed right away
+    fn read(&mut self, size: usize) -> Option<Vec<u8>> {
+        while self.data.len() < size {
+            let mut buffer = [0; 4096];
+            let mut ancillary_buffer = [0; 64];
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+            let read = self
+                .socket
+                .recv_vectored_with_ancillary(&mut [IoSliceMut::new(&mut buffer)], &mut ancillary)
+                .ok()?;
+            if read == 0 {
+                return None;
+            }
+
+            for data in ancillary.messages().flatten() {
+                if let AncillaryData::ScmRights(fds) = data {
+                    fds.for_each(|fd| drop(unsafe { OwnedFd::from_raw_fd(fd) }));
+                }
+            }
+            self.data.extend(&buffer[..read]);
+        }
+
+        Some(self.data.drain(..size).collect())
+    }
+}
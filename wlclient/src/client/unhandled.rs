@@ -0,0 +1,232 @@
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use log::warn;
+
+use super::{MockingListener, WaylandClient, WlObjectInterfaceInfo, NO_STATE};
+use crate::{
+    error::{fallback_error, Result},
+    protocol::{WaylandId, WlEventId, WlEventMsg, WlInterface, WlInterfaceId},
+    sync::MaybeSend,
+};
+
+pub trait UnhandledEventHandler<S>: FnMut(&mut S, &mut WaylandClient<S>, UnhandledEvent) + MaybeSend {}
+impl<S, F> UnhandledEventHandler<S> for F where
+    F: FnMut(&mut S, &mut WaylandClient<S>, UnhandledEvent) + MaybeSend
+{
+}
+
+/// What to do with an event that none of the listeners of its object consumed (and for which
+/// there is no default handler installed for the object's interface).
+pub enum UnhandledPolicy<S> {
+    Ignore,
+    /// Logs a warning the first time each event of an interface isn't handled.
+    LogOnce,
+    /// Makes the dispatch return an error.
+    Error,
+    Fallback(Box<dyn UnhandledEventHandler<S>>),
+}
+
+impl<S> UnhandledPolicy<S> {
+    pub fn fallback<F: UnhandledEventHandler<S> + 'static>(handler: F) -> Self {
+        Self::Fallback(Box::new(handler))
+    }
+}
+
+pub struct UnhandledEvent {
+    pub object_id: WaylandId,
+    pub interface: &'static str,
+    pub event_id: WlEventId,
+    msg: Box<dyn Any>,
+}
+
+impl UnhandledEvent {
+    /// Returns the parsed event if `T` is the interface of the object that received it.
+    pub fn downcast<T: WlInterface<Event = E>, E: 'static>(self) -> Option<WlEventMsg<E>> {
+        WlEventMsg::from_any(self.msg)
+    }
+}
+
+pub(super) struct UnhandledEvents<S> {
+    policy: UnhandledPolicy<S>,
+    interface_policies: HashMap<WlInterfaceId, UnhandledPolicy<S>>,
+    default_handlers: HashMap<WlInterfaceId, MockingListener<S>>,
+    logged: HashSet<(WlInterfaceId, WlEventId)>,
+    // The fallbacks being run (`None` for the one of the policy of every interface), which are
+    // taken out of their policy in the meantime. Setting the policy clears the flag so that the
+    // fallback isn't restored over it.
+    running: HashSet<Option<WlInterfaceId>>,
+}
+
+impl<S> UnhandledEvents<S> {
+    pub(super) fn new() -> Self {
+        Self {
+            policy: UnhandledPolicy::Error,
+            interface_policies: HashMap::new(),
+            default_handlers: HashMap::new(),
+            logged: HashSet::new(),
+            running: HashSet::new(),
+        }
+    }
+
+    pub(super) fn has_default_handler(&self, interface_id: WlInterfaceId) -> bool {
+        self.default_handlers.contains_key(&interface_id)
+    }
+}
+
+impl<S> WaylandClient<S> {
+    /// Sets the policy used for the interfaces without a policy of their own.
+    pub fn set_unhandled_policy(&mut self, policy: UnhandledPolicy<S>) {
+        let mut unhandled = self.unhandled.lock();
+        unhandled.running.remove(&None);
+        unhandled.policy = policy;
+    }
+
+    pub fn set_interface_unhandled_policy<T: WlInterface>(&mut self, policy: UnhandledPolicy<S>) {
+        let mut unhandled = self.unhandled.lock();
+        unhandled.running.remove(&Some(T::get_interface_id()));
+        unhandled.interface_policies.insert(T::get_interface_id(), policy);
+    }
+
+    /// Installs `handler` for the events of every object of the interface `T` that weren't
+    /// consumed by the listeners of the object itself.
+    pub fn set_default_handler<T, E, F>(&mut self, mut handler: F)
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut S, &mut WaylandClient<S>, WlEventMsg<E>) + MaybeSend + 'static,
+        E: 'static,
+    {
+        self.insert_default_handler::<T, E, _>(move |client, state, msg| {
+            handler(state.expect(NO_STATE), client, msg)
+        });
+    }
+
+    fn insert_default_handler<T, E, F>(&mut self, mut handler: F)
+    where
+        T: WlInterface<Event = E>,
+        F: FnMut(&mut WaylandClient<S>, Option<&mut S>, WlEventMsg<E>) + MaybeSend + 'static,
+        E: 'static,
+    {
+        self.unhandled.lock().default_handlers.insert(
+            T::get_interface_id(),
+            Box::new(move |client, state, msg| match WlEventMsg::from_any(msg) {
+                Some(msg) => {
+                    handler(client, state, msg);
+                    None
+                }
+                None => panic!("Unable to get WlEventMsg<...> for {}", T::get_display_name()),
+            }),
+        );
+    }
+
+    pub fn remove_default_handler<T: WlInterface>(&mut self) {
+        self.unhandled
+            .lock()
+            .default_handlers
+            .remove(&T::get_interface_id());
+    }
+
+    pub(super) fn handle_unhandled(
+        &mut self,
+        object_id: WaylandId,
+        interface: WlObjectInterfaceInfo,
+        event_id: WlEventId,
+        msg: Box<dyn Any>,
+        mut state: Option<&mut S>,
+    ) -> Result<()> {
+        let handler = self.unhandled.lock().default_handlers.remove(&interface.id);
+        let msg = match handler {
+            Some(mut handler) => {
+                let msg = handler(self, state.as_deref_mut(), msg);
+                // keep the handler unless it was replaced in the meantime
+                let mut unhandled = self.unhandled.lock();
+                unhandled.default_handlers.entry(interface.id).or_insert(handler);
+                msg
+            }
+            None => Some(msg),
+        };
+
+        // the client already took what it needed from it
+        let Some(msg) = msg.filter(|_| !self.core_handlers.lock().contains_key(&interface.id)) else {
+            return Ok(());
+        };
+
+        let mut unhandled = self.unhandled.lock();
+        // the interface policy is taken out while its fallback is running
+        let id = interface.id;
+        let has_policy = unhandled.interface_policies.contains_key(&id);
+        let key = (has_policy || unhandled.running.contains(&Some(id))).then_some(id);
+
+        if unhandled.running.contains(&key) {
+            // e.g. read by a roundtrip of the fallback, which can't be called again
+            warn!(
+                "Dropping event {event_id} of object {object_id} @ {}, its fallback is already running",
+                interface.display_name
+            );
+            return Ok(());
+        }
+
+        let policy = match key {
+            Some(id) => &unhandled.interface_policies[&id],
+            None => &unhandled.policy,
+        };
+
+        match policy {
+            UnhandledPolicy::Ignore => Ok(()),
+            UnhandledPolicy::LogOnce => {
+                if unhandled.logged.insert((interface.id, event_id)) {
+                    warn!(
+                        "No handler for object {object_id} @ {} (received event {event_id})",
+                        interface.display_name
+                    );
+                }
+                Ok(())
+            }
+            UnhandledPolicy::Error => Err(fallback_error!(
+                "No handler object {object_id} @ {} (received event {event_id})",
+                interface.display_name
+            )),
+            UnhandledPolicy::Fallback(_) => {
+                let Some(state) = state else {
+                    warn!("Unable to run the fallback handler without the application state");
+                    return Ok(());
+                };
+
+                let policy = match key {
+                    Some(id) => unhandled.interface_policies.remove(&id),
+                    None => Some(mem::replace(&mut unhandled.policy, UnhandledPolicy::Ignore)),
+                };
+                let Some(UnhandledPolicy::Fallback(mut handler)) = policy else {
+                    unreachable!()
+                };
+                unhandled.running.insert(key);
+                drop(unhandled);
+
+                handler(
+                    state,
+                    self,
+                    UnhandledEvent {
+                        object_id,
+                        interface: interface.display_name,
+                        event_id,
+                        msg,
+                    },
+                );
+
+                // restore the fallback unless the policy was set in the meantime
+                let mut unhandled = self.unhandled.lock();
+                if unhandled.running.remove(&key) {
+                    let policy = UnhandledPolicy::Fallback(handler);
+                    match key {
+                        Some(id) => unhandled.interface_policies.insert(id, policy),
+                        None => Some(mem::replace(&mut unhandled.policy, policy)),
+                    };
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,19 +1,49 @@
-use crate::protocol;
+use crate::{client::memory, protocol};
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     // clients errors
     NoSuchObject,
-    HandlerAlreadlyInPlace,
+    NoSuchListener,
     InvalidInterface,
+    NoSuchQueue,
+    NoSuchGlobal,
+    UnsupportedVersion { interface: &'static str, advertised: u32 },
+    // nothing was received before the deadline, the connection can still be used
+    Timeout,
 
     // other modules errors
     WlProtocolError(protocol::Error),
+    Memory(memory::Error),
     Context { error: Box<Error>, message: String },
     FallBack(Box<dyn std::error::Error>),
 }
 
+impl Error {
+    /// Whether the connection can't be used anymore after this error.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::WlProtocolError(
+                protocol::Error::Fatal(_)
+                | protocol::Error::Disconnected
+                | protocol::Error::InvalidMessageSize(_),
+            ) => true,
+            Error::Context { error, .. } => error.is_fatal(),
+            _ => false,
+        }
+    }
+
+    /// Whether a deadline expired (e.g. [`crate::client::WaylandClient::dispatch_timeout`]).
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout => true,
+            Error::Context { error, .. } => error.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
 // https://github.com/dtolnay/case-studies/blob/master/autoref-specialization/README.md
 impl<T> From<T> for Error
 where
@@ -30,6 +60,12 @@ impl From<protocol::Error> for Error {
     }
 }
 
+impl From<memory::Error> for Error {
+    fn from(value: memory::Error) -> Self {
+        Error::Memory(value)
+    }
+}
+
 macro_rules! fallback_error {
     ($($t : tt)*) => { crate::error::Error::FallBack(format!($($t)*).into()) }
 }
@@ -8,6 +8,7 @@
 pub mod client;
 pub mod error;
 pub mod protocol;
+pub mod sync;
 
 pub use error::{Error, Result};
 pub use client::WaylandClient;
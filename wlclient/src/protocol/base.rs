@@ -3,9 +3,8 @@ use super::declare_interfaces;
 
 declare_interfaces !{
     @FirstId = 0,
-    WlRegion,
 
-    @interface(WlCallBack) { @events { done(data : u32); } },
+    @interface(WlCallBack as "wl_callback") { @events { done(data : u32); } },
 
     @interface(WlDisplay) {
         @requests {
@@ -17,6 +16,8 @@ declare_interfaces !{
             error(object_id: u32, code: u32, message: String);
             delete_id(id: u32);
         }
+
+        @errors { invalid_object = 0, invalid_method = 1, no_memory = 2, implementation = 3, }
     },
 
     @interface(WlRegistry) {
@@ -26,23 +27,36 @@ declare_interfaces !{
             ];
         }
 
-        @events { global(name: u32, interface: String, version: u32); }
+        @events {
+            global(name: u32, interface: String, version: u32);
+            global_remove(name: u32);
+        }
     },
 
-    @interface(WlCompositor) {
+    @interface(WlCompositor, version = 6) {
         @requests {
             create_surface(surface: &WlSurface) => [ Uint32(surface.get_object_id()) ];
+            create_region(region: &WlRegion) => [ Uint32(region.get_object_id()) ];
+        }
+    },
+
+    @interface(WlRegion) {
+        @requests {
+            @destructor destroy();
+            add(x: i32, y: i32, width: i32, height: i32) => [ Int32(x), Int32(y), Int32(width), Int32(height) ];
+            subtract(x: i32, y: i32, width: i32, height: i32) => [ Int32(x), Int32(y), Int32(width), Int32(height) ];
         }
     },
 
-    @interface(WlSurface){
+    @interface(WlSurface, version = 6){
         @requests {
-            destroy();
+            @destructor destroy();
             attach(buffer : &WlBuffer, x : i32, y : i32)    => [ Uint32(buffer.get_object_id()), Int32(x), Int32(y) ];
             damage(x: i32, y: i32, width: i32, height: i32) => [ Int32(x), Int32(y), Int32(width), Int32(height) ];
             frame(callback: &WlCallBack)         => [ Uint32(callback.get_object_id()) ];
-            set_opaque_region(region: &WlRegion) => [ Uint32(region.get_object_id()) ];
-            set_input_region(region: &WlRegion)  => [ Uint32(region.get_object_id()) ];
+            // `None` resets the region (to empty for the opaque one, to infinite for the input one)
+            set_opaque_region(region: Option<&WlRegion>) => [ Uint32(region.map_or(0, |r| r.get_object_id())) ];
+            set_input_region(region: Option<&WlRegion>)  => [ Uint32(region.map_or(0, |r| r.get_object_id())) ];
             commit();
             set_buffer_transform(transformation : i32) => [ Int32(transformation) ]; // TODO: add enum for transformation
             set_buffer_scale(scale : i32) => [ Int32(scale) ];
@@ -55,6 +69,11 @@ declare_interfaces !{
             preferred_buffer_scale(factor : i32);
             preferred_buffer_transform(output_transform : u32);
         }
+
+        @errors {
+            invalid_scale = 0, invalid_transform = 1, invalid_size = 2,
+            invalid_offset = 3, defunct_role_object = 4,
+        }
     },
 
     // shared memory stuffs
@@ -65,6 +84,7 @@ declare_interfaces !{
             ];
         }
         @events { format( format_value : u32 ); } 
+        @errors { invalid_format = 0, invalid_stride = 1, invalid_fd = 2, }
     },
 
     @interface(WlShmPool) {
@@ -73,22 +93,265 @@ declare_interfaces !{
                 Uint32(buffer.get_object_id()), Int32(offset), Int32(width),
                 Int32(height), Int32(stride), Uint32(format as u32),
             ];
-            destroy();
+            @destructor destroy();
             resize(size: i32) => [Int32(size)];
         }
     },
 
     @interface(WlBuffer) { 
-        @requests { destroy(); } 
+        @requests { @destructor destroy(); } 
         @events   { release(); } 
     },
+
+    // input devices, the coordinates and axis values are in wl_fixed (see `fixed_to_f64`)
+    @interface(WlSeat, version = 9) {
+        @requests {
+            get_pointer(pointer: &WlPointer)    => [ Uint32(pointer.get_object_id()) ];
+            get_keyboard(keyboard: &WlKeyboard) => [ Uint32(keyboard.get_object_id()) ];
+            get_touch(touch: &WlTouch)          => [ Uint32(touch.get_object_id()) ];
+            @destructor release(); // since version 5
+        }
+
+        @events {
+            capabilities(capabilities: u32);
+            name(name: String);
+        }
+
+        @errors { missing_capability = 0, }
+    },
+
+    @interface(WlPointer, version = 9) {
+        @requests {
+            set_cursor(serial: u32, surface: Option<&WlSurface>, hotspot_x: i32, hotspot_y: i32) => [
+                Uint32(serial), Uint32(surface.map_or(0, |s| s.get_object_id())), Int32(hotspot_x), Int32(hotspot_y),
+            ];
+            @destructor release(); // since version 3
+        }
+
+        @events {
+            enter(serial: u32, surface: u32, surface_x: i32, surface_y: i32);
+            leave(serial: u32, surface: u32);
+            motion(time: u32, surface_x: i32, surface_y: i32);
+            button(serial: u32, time: u32, button: u32, state: u32);
+            axis(time: u32, axis: u32, value: i32);
+            frame();
+            axis_source(axis_source: u32);
+            axis_stop(time: u32, axis: u32);
+            axis_discrete(axis: u32, discrete: i32);
+            axis_value120(axis: u32, value120: i32);
+            axis_relative_direction(axis: u32, direction: u32);
+        }
+
+        @errors { role = 0, }
+    },
+
+    @interface(WlKeyboard, version = 9) {
+        @requests { @destructor release(); } // since version 3
+
+        @events {
+            keymap(format: u32, file_descriptor: OwnedFd, size: u32);
+            enter(serial: u32, surface: u32, keys: Array);
+            leave(serial: u32, surface: u32);
+            key(serial: u32, time: u32, key: u32, state: u32);
+            modifiers(serial: u32, mods_depressed: u32, mods_latched: u32, mods_locked: u32, group: u32);
+            repeat_info(rate: i32, delay: i32);
+        }
+    },
+
+    @interface(WlTouch, version = 9) {
+        @requests { @destructor release(); } // since version 3
+
+        @events {
+            down(serial: u32, time: u32, surface: u32, id: i32, x: i32, y: i32);
+            up(serial: u32, time: u32, id: i32);
+            motion(time: u32, id: i32, x: i32, y: i32);
+            frame();
+            cancel();
+            shape(id: i32, major: i32, minor: i32);
+            orientation(id: i32, orientation: i32);
+        }
+    },
+}
+
+/// Values of `wl_seat.capabilities`.
+pub mod seat_capabilities {
+    pub const POINTER: u32 = 1;
+    pub const KEYBOARD: u32 = 2;
+    pub const TOUCH: u32 = 4;
 }
 
 
-#[repr(u32)]
-pub enum WlShmFormat {
-    Argb8888 = 0,
-    Xrgb8888 = 1	
+macro_rules! shm_formats {
+    ($($name:ident = $code:literal => $bits:tt,)*) => {
+        /// Pixel formats of `wl_shm`. Except for `Argb8888` and `Xrgb8888` (which every compositor
+        /// supports) the values are DRM fourcc codes.
+        #[repr(u32)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum WlShmFormat { $($name = $code,)* }
+
+        impl TryFrom<u32> for WlShmFormat {
+            type Error = crate::error::Error;
+
+            fn try_from(value: u32) -> crate::error::Result<Self> {
+                match value {
+                    $($code => Ok(Self::$name),)*
+                    _ => Err(crate::error::fallback_error!("Unknown wl_shm format {value:#x}")),
+                }
+            }
+        }
+
+        impl WlShmFormat {
+            /// Size of a pixel, `None` for the formats with several planes (or tiles).
+            pub fn bits_per_pixel(self) -> Option<u32> {
+                match self { $(Self::$name => shm_formats!(@bits $bits),)* }
+            }
+        }
+    };
+
+    (@bits None) => { None };
+    (@bits $bits:literal) => { Some($bits) };
+}
+
+shm_formats! {
+    Argb8888               = 0x00000000 => 32,
+    Xrgb8888               = 0x00000001 => 32,
+    C8                     = 0x20203843 => 8,
+    Rgb332                 = 0x38424752 => 8,
+    Bgr233                 = 0x38524742 => 8,
+    Xrgb4444               = 0x32315258 => 16,
+    Xbgr4444               = 0x32314258 => 16,
+    Rgbx4444               = 0x32315852 => 16,
+    Bgrx4444               = 0x32315842 => 16,
+    Argb4444               = 0x32315241 => 16,
+    Abgr4444               = 0x32314241 => 16,
+    Rgba4444               = 0x32314152 => 16,
+    Bgra4444               = 0x32314142 => 16,
+    Xrgb1555               = 0x35315258 => 16,
+    Xbgr1555               = 0x35314258 => 16,
+    Rgbx5551               = 0x35315852 => 16,
+    Bgrx5551               = 0x35315842 => 16,
+    Argb1555               = 0x35315241 => 16,
+    Abgr1555               = 0x35314241 => 16,
+    Rgba5551               = 0x35314152 => 16,
+    Bgra5551               = 0x35314142 => 16,
+    Rgb565                 = 0x36314752 => 16,
+    Bgr565                 = 0x36314742 => 16,
+    Rgb888                 = 0x34324752 => 24,
+    Bgr888                 = 0x34324742 => 24,
+    Xbgr8888               = 0x34324258 => 32,
+    Rgbx8888               = 0x34325852 => 32,
+    Bgrx8888               = 0x34325842 => 32,
+    Abgr8888               = 0x34324241 => 32,
+    Rgba8888               = 0x34324152 => 32,
+    Bgra8888               = 0x34324142 => 32,
+    Xrgb2101010            = 0x30335258 => 32,
+    Xbgr2101010            = 0x30334258 => 32,
+    Rgbx1010102            = 0x30335852 => 32,
+    Bgrx1010102            = 0x30335842 => 32,
+    Argb2101010            = 0x30335241 => 32,
+    Abgr2101010            = 0x30334241 => 32,
+    Rgba1010102            = 0x30334152 => 32,
+    Bgra1010102            = 0x30334142 => 32,
+    Yuyv                   = 0x56595559 => 16,
+    Yvyu                   = 0x55595659 => 16,
+    Uyvy                   = 0x59565955 => 16,
+    Vyuy                   = 0x59555956 => 16,
+    Ayuv                   = 0x56555941 => 32,
+    Nv12                   = 0x3231564e => None,
+    Nv21                   = 0x3132564e => None,
+    Nv16                   = 0x3631564e => None,
+    Nv61                   = 0x3136564e => None,
+    Yuv410                 = 0x39565559 => None,
+    Yvu410                 = 0x39555659 => None,
+    Yuv411                 = 0x31315559 => None,
+    Yvu411                 = 0x31315659 => None,
+    Yuv420                 = 0x32315559 => None,
+    Yvu420                 = 0x32315659 => None,
+    Yuv422                 = 0x36315559 => None,
+    Yvu422                 = 0x36315659 => None,
+    Yuv444                 = 0x34325559 => None,
+    Yvu444                 = 0x34325659 => None,
+    R8                     = 0x20203852 => 8,
+    R16                    = 0x20363152 => 16,
+    Rg88                   = 0x38384752 => 16,
+    Gr88                   = 0x38385247 => 16,
+    Rg1616                 = 0x32334752 => 32,
+    Gr1616                 = 0x32335247 => 32,
+    Xrgb16161616f          = 0x48345258 => 64,
+    Xbgr16161616f          = 0x48344258 => 64,
+    Argb16161616f          = 0x48345241 => 64,
+    Abgr16161616f          = 0x48344241 => 64,
+    Xyuv8888               = 0x56555958 => 32,
+    Vuy888                 = 0x34325556 => 24,
+    Vuy101010              = 0x30335556 => 32,
+    Y210                   = 0x30313259 => 32,
+    Y212                   = 0x32313259 => 32,
+    Y216                   = 0x36313259 => 32,
+    Y410                   = 0x30313459 => 32,
+    Y412                   = 0x32313459 => 64,
+    Y416                   = 0x36313459 => 64,
+    Xvyu2101010            = 0x30335658 => 32,
+    Xvyu12_16161616        = 0x36335658 => 64,
+    Xvyu16161616           = 0x38345658 => 64,
+    Y0l0                   = 0x304c3059 => None,
+    X0l0                   = 0x304c3058 => None,
+    Y0l2                   = 0x324c3059 => None,
+    X0l2                   = 0x324c3058 => None,
+    Yuv420_8bit            = 0x38305559 => None,
+    Yuv420_10bit           = 0x30315559 => None,
+    Xrgb8888A8             = 0x38415258 => None,
+    Xbgr8888A8             = 0x38414258 => None,
+    Rgbx8888A8             = 0x38415852 => None,
+    Bgrx8888A8             = 0x38415842 => None,
+    Rgb888A8               = 0x38413852 => None,
+    Bgr888A8               = 0x38413842 => None,
+    Rgb565A8               = 0x38413552 => None,
+    Bgr565A8               = 0x38413542 => None,
+    Nv24                   = 0x3432564e => None,
+    Nv42                   = 0x3234564e => None,
+    P210                   = 0x30313250 => None,
+    P010                   = 0x30313050 => None,
+    P012                   = 0x32313050 => None,
+    P016                   = 0x36313050 => None,
+    Axbxgxrx106106106106   = 0x30314241 => 64,
+    Nv15                   = 0x3531564e => None,
+    Q410                   = 0x30313451 => None,
+    Q401                   = 0x31303451 => None,
+    Xrgb16161616           = 0x38345258 => 64,
+    Xbgr16161616           = 0x38344258 => 64,
+    Argb16161616           = 0x38345241 => 64,
+    Abgr16161616           = 0x38344241 => 64,
+    C1                     = 0x20203143 => 1,
+    C2                     = 0x20203243 => 2,
+    C4                     = 0x20203443 => 4,
+    D1                     = 0x20203144 => 1,
+    D2                     = 0x20203244 => 2,
+    D4                     = 0x20203444 => 4,
+    D8                     = 0x20203844 => 8,
+    R1                     = 0x20203152 => 1,
+    R2                     = 0x20203252 => 2,
+    R4                     = 0x20203452 => 4,
+    R10                    = 0x20303152 => 16,
+    R12                    = 0x20323152 => 16,
+    Avuy8888               = 0x59555641 => 32,
+    Xvuy8888               = 0x59555658 => 32,
+    P030                   = 0x30333050 => None,
+
+}
+
+impl WlShmFormat {
+    /// Size in bytes of a line of `width` pixels, see [`Self::bits_per_pixel`]. `None` as well
+    /// when the width is negative or the line doesn't fit in an `i32`.
+    pub fn stride(self, width: i32) -> Option<i32> {
+        let bits = self.bits_per_pixel()? as u64;
+        let bits = u64::try_from(width).ok()?.checked_mul(bits)?;
+        i32::try_from(bits.div_ceil(8)).ok()
+    }
+
+    /// Size in bytes of a `width` x `height` buffer with lines of [`Self::stride`] bytes.
+    pub fn buffer_size(self, width: i32, height: i32) -> Option<usize> {
+        (self.stride(width)? as usize).checked_mul(usize::try_from(height).ok()?)
+    }
 }
 
 //#[derive(Debug, Clone, Copy)]
@@ -125,3 +388,30 @@ pub enum WlShmFormat {
 //
 //    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strides_are_rounded_up_to_whole_bytes() {
+        assert_eq!(WlShmFormat::Argb8888.stride(10), Some(40));
+        assert_eq!(WlShmFormat::Rgb888.stride(3), Some(9));
+        assert_eq!(WlShmFormat::C1.stride(9), Some(2));
+        assert_eq!(WlShmFormat::Nv12.stride(10), None);
+    }
+
+    #[test]
+    fn strides_that_overflow_are_none() {
+        let format = WlShmFormat::Argb8888;
+        let max_stride = i32::MAX / 4 * 4;
+        assert_eq!(format.stride(i32::MAX / 4), Some(max_stride));
+        assert_eq!(format.stride(i32::MAX / 4 + 1), None);
+        assert_eq!(format.stride(i32::MAX), None);
+        assert_eq!(format.stride(-1), None);
+
+        let max_size = max_stride as usize * i32::MAX as usize;
+        assert_eq!(format.buffer_size(i32::MAX / 4, i32::MAX), Some(max_size));
+        assert_eq!(format.buffer_size(10, -1), None);
+    }
+}
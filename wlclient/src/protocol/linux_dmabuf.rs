@@ -0,0 +1,80 @@
+use super::macros::declare_interfaces;
+use super::base::{WlBuffer, WlSurface};
+
+declare_interfaces! {
+    @FirstId = 200,
+
+    // Only the v4 feedback is used to find the supported formats, the `format` and `modifier`
+    // events are deprecated since then.
+    @interface(ZwpLinuxDmabufV1, version = 4) {
+        @requests {
+            @destructor destroy();
+            create_params(params: &ZwpLinuxBufferParamsV1) => [ Uint32(params.get_object_id()) ];
+            get_default_feedback(feedback: &ZwpLinuxDmabufFeedbackV1) => [ Uint32(feedback.get_object_id()) ];
+            get_surface_feedback(feedback: &ZwpLinuxDmabufFeedbackV1, surface: &WlSurface) => [
+                Uint32(feedback.get_object_id()), Uint32(surface.get_object_id()),
+            ];
+        }
+
+        @events {
+            format(format: u32);
+            modifier(format: u32, modifier_hi: u32, modifier_lo: u32);
+        }
+    },
+
+    @interface(ZwpLinuxBufferParamsV1) {
+        @requests {
+            @destructor destroy();
+            add(file_descriptor: i32, plane_idx: u32, offset: u32, stride: u32, modifier: u64) => [
+                FileDesc(file_descriptor), Uint32(plane_idx), Uint32(offset), Uint32(stride),
+                Uint32((modifier >> 32) as u32), Uint32(modifier as u32),
+            ];
+            // the buffer of `created` is allocated by the server, which isn't supported yet, so
+            // `create_immed` has to be used instead
+            create(width: i32, height: i32, format: u32, flags: u32) => [
+                Int32(width), Int32(height), Uint32(format), Uint32(flags),
+            ];
+            create_immed(buffer: &WlBuffer, width: i32, height: i32, format: u32, flags: u32) => [
+                Uint32(buffer.get_object_id()), Int32(width), Int32(height), Uint32(format), Uint32(flags),
+            ];
+        }
+
+        @events {
+            created(buffer: u32);
+            failed();
+        }
+
+        @errors {
+            already_used = 0, plane_idx = 1, plane_set = 2, incomplete = 3,
+            invalid_format = 4, invalid_dimensions = 5, out_of_bounds = 6, invalid_wl_buffer = 7,
+        }
+    },
+
+    // the events are gathered into a `crate::client::dmabuf::DmabufFeedback`
+    @interface(ZwpLinuxDmabufFeedbackV1) {
+        @requests { @destructor destroy(); }
+
+        @events {
+            done();
+            format_table(file_descriptor: OwnedFd, size: u32);
+            main_device(device: Bytes);
+            tranche_done();
+            tranche_target_device(device: Bytes);
+            tranche_formats(indices: Bytes);
+            tranche_flags(flags: u32);
+        }
+    },
+}
+
+/// Flags of `zwp_linux_buffer_params_v1.create`.
+pub mod buffer_flags {
+    pub const Y_INVERT: u32 = 1;
+    pub const INTERLACED: u32 = 2;
+    pub const BOTTOM_FIRST: u32 = 4;
+}
+
+/// Flags of `zwp_linux_dmabuf_feedback_v1.tranche_flags`.
+pub mod tranche_flags {
+    /// The buffers might be scanned out directly.
+    pub const SCANOUT: u32 = 1;
+}
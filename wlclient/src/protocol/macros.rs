@@ -1,35 +1,45 @@
 /// Syntax in BNF format:
-/// ```
+/// ```text
 /// "declare_interfaces!" "{"
 ///    "@FirstId" "=" <first-interface-id-value> ","
-///    (<empty-interface-name>",")* // interfaces with no events or requests
-///    ( "@interface" "(" <interface-name> ")" "{"
+///    (<empty-interface-name> ("as" <wire-name>)? ",")* // interfaces with no events or requests
+///
+///    // The wire name defaults to the interface name in snake_case and the version to 1.
+///    ( "@interface" "(" <interface-name> ("as" <wire-name>)? ("," "version" "=" <version>)? ")" "{"
 ///        (
 ///        // TODO: think about getting rid of the []
 ///          "@requests" "{"
-///              (<request-name>"("(<arg> : <type>)*")" ("=>" "[" (<wire-values> ",")+ "]")? ";")+
+///              ("@destructor"? <request-name>"("(<arg> : <type>)*")" ("=>" "[" (<wire-values> ",")+ "]")? ";")+
 ///          "}"
 ///        )?
 ///
 ///        // An new enum named "<inteface-name>Event" will be generated and for for each <event-name> 
 ///        // there will be an back on this new enum in CamelCase.
+///        // Arguments of type `OwnedFd` are taken from the file descriptors received with the event,
+///        // `Array` is for arrays of u32 and `Bytes` for the others (e.g. a dev_t or u16 indices).
 ///        "@events" "{" 
 ///            (<event-name> "(" (<arg> ":" <type>)* ")" ";" )+ 
 ///        }
+///
+///        // Values of the interface error enum, used to decode the code of `wl_display.error`
+///        ( "@errors" "{" (<error-name> "=" <error-code> ",")+ "}" )?
 ///    "}" ",")* 
 /// "}"
 /// ```
 macro_rules! declare_interfaces {
     {  
        @FirstId = $start : expr,
-       $($skeleton : ident),* 
-       $($(,)? @interface($name: ident) $({
+       $($skeleton : ident $(as $skeleton_wire : literal)?),* 
+       $($(,)? @interface($name: ident $(as $wire : literal)? $(, version = $version : literal)?) $({
             $(@requests {
                 $($rt : tt)+
             })?
             $(@events {
                 $($et : tt)+
             })?
+            $(@errors {
+                $($errt : tt)+
+            })?
        })?),* $(,)?
     } => {
         use super::wire_format::parsing as parser;
@@ -46,14 +56,17 @@ macro_rules! declare_interfaces {
 
         paste::paste! {
             $(
-                declare_interfaces!(@decl $name $($(, [< $name Event>], $($et)+ )?)?);
+                declare_interfaces!(
+                    @decl $name, [$($wire)?], [$($version)?], [$($($($errt)+)?)?] 
+                    $($(, [< $name Event>], $($et)+ )?)?
+                );
                 $(
                     $(declare_interfaces!(@requests $name, $($rt)+);)?
                 )?
             )*
         }
 
-        $(declare_interfaces!(@decl $skeleton);)*
+        $(declare_interfaces!(@decl $skeleton, [$($skeleton_wire)?], []);)*
     };
 
     (@requests $name : ident, $($t : tt)+) => {
@@ -63,9 +76,24 @@ macro_rules! declare_interfaces {
     };
 
     (@next_request $id : expr,) => { }; 
+    (
+        @next_request $id : expr, 
+        @destructor $request : ident ($($args : tt)*) $(=> [$($expr : expr),+ $(,)? ])?; $($t : tt)*
+    ) => {
+        declare_interfaces!(@request_fn $id, true, $request ($($args)*) $(=> [$($expr),+])?);
+        declare_interfaces!(@next_request $id + 1, $($t)*);
+    };
     (
         @next_request $id : expr, 
         $request : ident ($($args : tt)*) $(=> [$($expr : expr),+ $(,)? ])?; $($t : tt)*
+    ) => {
+        declare_interfaces!(@request_fn $id, false, $request ($($args)*) $(=> [$($expr),+])?);
+        declare_interfaces!(@next_request $id + 1, $($t)*);
+    };
+
+    (
+        @request_fn $id : expr, $destructor : expr,
+        $request : ident ($($args : tt)*) $(=> [$($expr : expr),+])?
     ) => {
         pub fn $request (&self, $($args)* ) -> Result<usize> {
             let values = &[$($($expr,)+)?];
@@ -78,11 +106,13 @@ macro_rules! declare_interfaces {
 
             self.0.stream.send(WireMessage {
                 object_id: self.get_object_id(),
+                interface: Self::get_interface_name(),
                 request_id: $id,
+                generation: self.0.generation,
+                destructor: $destructor,
                 values
             })
         }
-        declare_interfaces!(@next_request $id + 1, $($t)*);
     };
 
     (@events $t : tt) => { }; 
@@ -101,11 +131,19 @@ macro_rules! declare_interfaces {
         }
     };
 
-    (@decl $name : ident) => {
-        declare_interfaces!(@decl $name, EmptyEvent);
+    (@decl $name : ident, [$($wire : tt)?], [$($version : tt)?]) => {
+        declare_interfaces!(@decl $name, [$($wire)?], [$($version)?], []);
+    };
+
+    (@decl $name : ident, [$($wire : tt)?], [$($version : tt)?], [$($errors : tt)*]) => {
+        declare_interfaces!(@decl $name, [$($wire)?], [$($version)?], [$($errors)*], EmptyEvent);
     };
 
-    (@decl $name : ident, $event_type : ident $(, $($type_def: tt)+)?) => {
+    (
+        @decl $name : ident, [$($wire : tt)?], [$($version : tt)?],
+        [$($error : ident = $code : literal),* $(,)?], 
+        $event_type : ident $(, $($type_def: tt)+)?
+    ) => {
         $(
             declare_interfaces!(@events $event_type, $($type_def)+);
         )?
@@ -124,27 +162,76 @@ macro_rules! declare_interfaces {
                 WlIds::$name as WlInterfaceId
             }
 
-            fn build(object_id: WaylandId, stream: std::rc::Rc<dyn WaylandStream>) -> Self {
-                Self(WlObjectMetaData { object_id, stream })
+            fn build(object_id: WaylandId, stream: StreamRef, user_data: Option<UserData>) -> Self {
+                let generation = stream.generation(object_id);
+                Self(WlObjectMetaData { object_id, generation, stream, user_data })
+            }
+
+            fn get_user_data(&self) -> Option<&UserData> {
+                self.0.user_data.as_ref()
             }
 
             fn get_display_name() -> &'static str {
                 stringify!($name)
             }
 
+            fn get_interface_name() -> &'static str {
+                declare_interfaces!(@or $($wire)?; paste::paste!(stringify!([<$name:snake>])))
+            }
+
+            fn get_max_version() -> u32 {
+                declare_interfaces!(@or $($version)?; 1)
+            }
+
+            fn get_error_name(code: u32) -> Option<&'static str> {
+                match code {
+                    $($code => Some(stringify!($error)),)*
+                    _ => None,
+                }
+            }
+
+            fn get_event_fds(event_id: WlEventId) -> usize {
+                $( declare_interfaces!(@next_event_fds event_id, 0, $($type_def)+); )?
+                0
+            }
+
             fn parse_event(
                 object_id: WaylandId,
                 event_id: WlEventId,
                 iter: &mut impl Iterator<Item = u8>,
+                fds: &mut impl Iterator<Item = OwnedFd>,
             ) -> Result<Self::Event> {
-                $( declare_interfaces!(@next_event object_id, event_id, iter, 0, $($type_def)+); )?
+                $( declare_interfaces!(@next_event object_id, event_id, iter, fds, 0, $($type_def)+); )?
                 Err(Error::NoEvent(event_id))
             }
         }
     };
 
-    (@next_event $obj_id : ident, $event_id : ident, $iter : ident, $id : expr,) => { };
-    (@next_event $obj_id : ident, $event_id : ident, $iter: ident, $id : expr, 
+    (@or $value : tt; $($default : tt)*) => { $value };
+    (@or ; $($default : tt)*) => { $($default)* };
+
+    (@next_event_fds $event_id : ident, $id : expr,) => { };
+    (@next_event_fds $event_id : ident, $id : expr,
+            $event_name : ident $(
+                ($($arg : ident $t : tt $type : ty),*)
+             )?; $($rem : tt)*) => {
+
+        // paste turns the types back into tokens, a forwarded `ty` can't be matched by
+        // `@fd_count OwnedFd` otherwise
+        paste::paste! {
+            if $event_id == $id {
+                return 0 $($(+ declare_interfaces!(@fd_count $type))*)?;
+            }
+        }
+
+        declare_interfaces!(@next_event_fds $event_id, $id + 1, $($rem)*);
+    };
+
+    (@fd_count OwnedFd) => { 1 };
+    (@fd_count $other : ty) => { 0 };
+
+    (@next_event $obj_id : ident, $event_id : ident, $iter : ident, $fds : ident, $id : expr,) => { };
+    (@next_event $obj_id : ident, $event_id : ident, $iter: ident, $fds : ident, $id : expr, 
             $event_name : ident $(
                 ($($arg : ident $t : tt $type : ty),*)
              )?; $($rem : tt)*) => {
@@ -152,7 +239,7 @@ macro_rules! declare_interfaces {
         paste::paste! {
             if $event_id == $id {
                 $($(
-                    let $arg = declare_interfaces!(@parse_arg $type, $iter);
+                    let $arg = declare_interfaces!(@parse_arg $type, $iter, $fds);
                 )*)?
 
                 let args : &[String] = &[$($(format!("{:?}",$arg),)*)?];
@@ -166,27 +253,35 @@ macro_rules! declare_interfaces {
             }
         }
 
-        declare_interfaces!(@next_event $obj_id, $event_id, $iter, $id + 1, $($rem)*);
+        declare_interfaces!(@next_event $obj_id, $event_id, $iter, $fds, $id + 1, $($rem)*);
     };
 
-    (@parse_arg String, $iter : ident) => {
+    (@parse_arg String, $iter : ident, $fds : ident) => {
         parser::parse_str($iter)?
     };
 
-    (@parse_arg u32, $iter : ident) => {
+    (@parse_arg u32, $iter : ident, $fds : ident) => {
         parser::parse_u32($iter)?
     };
 
-    (@parse_arg i32, $iter : ident) => {
+    (@parse_arg i32, $iter : ident, $fds : ident) => {
         parser::parse_i32($iter)?
     };
 
-    (@parse_arg Array, $iter : ident) => {
+    (@parse_arg Array, $iter : ident, $fds : ident) => {
         parser::parse_u32_array($iter)?
     };
 
-    (@parse_arg $other : ty, $iter : ident) => {
-        compile_error!("Events arguments types should be either 'u32', 'String', 'i32' or 'Array'");
+    (@parse_arg Bytes, $iter : ident, $fds : ident) => {
+        parser::parse_byte_array($iter)?
+    };
+
+    (@parse_arg OwnedFd, $iter : ident, $fds : ident) => {
+        parser::parse_fd($fds)?
+    };
+
+    (@parse_arg $other : ty, $iter : ident, $fds : ident) => {
+        compile_error!("Events arguments types should be either 'u32', 'String', 'i32', 'Array', 'Bytes' or 'OwnedFd'");
     };
 }
 
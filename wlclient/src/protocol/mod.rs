@@ -1,5 +1,5 @@
 #![allow(unused)]
-use std::{any::Any, fmt, rc::Rc};
+use std::{any::Any, fmt, os::fd::OwnedFd};
 use std::io::Error as IoError;
 use wire_format::parsing;
 
@@ -8,18 +8,42 @@ pub use self::WireValue::*;
 #[allow(unused_imports)]
 use macros::declare_interfaces;
 
-pub use wire_format::{WireMsgHeader, ClientStream};
+pub use wire_format::{is_disconnect, ClientStream, WireMsgHeader};
+use crate::sync::{MaybeSend, MaybeSync, Shared};
 
 pub mod base;
+pub mod linux_dmabuf;
+pub mod single_pixel_buffer;
+pub mod viewporter;
 pub mod wire_format;
 pub mod xdg_shell;
 mod macros;
 
 pub type WlInterfaceId = u32;
 pub type WaylandId = u32;
+
+// Object ids are split between the ones allocated by the client and the ones allocated by the
+// server (ids of objects created through events), 0 is the null object.
+pub const CLIENT_MIN_ID: WaylandId = 0x00000001;
+pub const CLIENT_MAX_ID: WaylandId = 0xfeffffff;
+pub const SERVER_MIN_ID: WaylandId = 0xff000000;
+pub const SERVER_MAX_ID: WaylandId = 0xffffffff;
+
 pub type WlEventId = u16;
 pub type Array = Vec<u32>;
+pub type Bytes = Vec<u8>;
 pub type EmptyEvent = ();
+
+/// Converts a `wl_fixed` (a signed 24.8 fixed point number).
+pub fn fixed_to_f64(value: i32) -> f64 {
+    value as f64 / 256.0
+}
+
+/// Converts to a `wl_fixed`, rounded to the nearest 1/256.
+pub fn f64_to_fixed(value: f64) -> i32 {
+    (value * 256.0).round() as i32
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
@@ -46,26 +70,68 @@ impl fmt::Display for WireValue {
 #[derive(Debug)]
 pub struct WireMessage<'a> {
     pub object_id: WaylandId,
+    // wire name of the interface of the object
+    pub interface: &'static str,
     pub request_id: WaylandId,
+    // of the object id when the proxy was created, see `WaylandStream::generation`
+    pub generation: u32,
+    // destructor requests make the object unusable
+    pub destructor: bool,
     pub values: &'a [WireValue],
 }
 
 // Implementation of this trait are recommended to use interior mutability
 // (https://doc.rust-lang.org/reference/interior-mutability.html)
-pub trait WaylandStream {
+pub trait WaylandStream: MaybeSend + MaybeSync {
     fn send(&self, msg: WireMessage) -> Result<usize>;
+    /// Number of times `object_id` was deleted, so that the proxies of a deleted object can't
+    /// send requests to the object that reuses its id.
+    fn generation(&self, object_id: WaylandId) -> u32;
 }
 
+pub type StreamRef = Shared<dyn WaylandStream>;
+
 // TODO: fix this later
 pub struct RawMessage {
     pub object_id: WaylandId,
     pub event_id: u16,
     pub payload: Box<[u8]>,
+    pub fds: Vec<OwnedFd>,
+}
+
+/// Data attached to an object when it is created, see [`WlInterface::user_data`].
+///
+/// It is shared by every proxy of the object, so it can only be mutated through interior
+/// mutability (e.g. [`crate::sync::Lock`]).
+pub type UserData = Shared<dyn AnyData>;
+
+/// [`Any`] that is also `Send + Sync` with the `thread-safe` feature.
+pub trait AnyData: Any + MaybeSend + MaybeSync {
+    fn as_any(&self) -> &dyn Any;
+    fn type_name(&self) -> &'static str;
+}
+
+impl<T: Any + MaybeSend + MaybeSync> AnyData for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
 }
 
 pub struct WlEventMsg<E> {
     pub object_id: WaylandId,
     pub event: E,
+    pub user_data: Option<UserData>,
+}
+
+impl<E> WlEventMsg<E> {
+    /// The data attached to the object that received the event, if it is a `D`.
+    pub fn user_data<D: Any>(&self) -> Option<&D> {
+        self.user_data.as_deref()?.as_any().downcast_ref()
+    }
 }
 
 impl<E: 'static> WlEventMsg<E> {
@@ -86,20 +152,39 @@ pub trait WlInterface {
     fn get_object_id(&self) -> WaylandId;
     fn get_interface_id() -> WlInterfaceId;
     fn get_display_name() -> &'static str { "" }
+    /// Name of the interface on the wire (e.g. `wl_compositor`), used to bind globals.
+    fn get_interface_name() -> &'static str;
+    /// Highest version of the interface that is implemented.
+    fn get_max_version() -> u32 { 1 }
+    /// Name of the entry of the interface error enum with value `code`.
+    fn get_error_name(code: u32) -> Option<&'static str> { None }
+
+    fn build(object_id: WaylandId, stream: StreamRef, user_data: Option<UserData>) -> Self;
 
-    fn build(object_id: WaylandId, stream: Rc<dyn WaylandStream>) -> Self;
+    fn get_user_data(&self) -> Option<&UserData>;
+
+    /// The data attached to the object when it was created, if it is a `D`.
+    fn user_data<D: Any>(&self) -> Option<&D> {
+        let data: &dyn AnyData = &**self.get_user_data()?;
+        data.as_any().downcast_ref()
+    }
+
+    /// Number of file descriptors carried by the event.
+    fn get_event_fds(event_id: WlEventId) -> usize;
 
     fn parse_event(
         object_id: WaylandId,
         event_id: WlEventId,
         iter: &mut impl Iterator<Item = u8>,
+        fds: &mut impl Iterator<Item = OwnedFd>,
     ) -> Result<Self::Event>;
 
     fn parse_msg(msg: RawMessage) -> Result<WlEventMsg<Self::Event>> {
         let object_id = msg.object_id;
         let event_id = msg.event_id;
         let mut iter = msg.payload.iter().copied();
-        let event = Self::parse_event(object_id, event_id, &mut iter)?;
+        let mut fds = msg.fds.into_iter();
+        let event = Self::parse_event(object_id, event_id, &mut iter, &mut fds)?;
 
         let remaining = iter.count();
         if remaining != 0 {
@@ -108,14 +193,30 @@ pub trait WlInterface {
             });
         }
 
-        Ok(WlEventMsg { object_id, event })
+        Ok(WlEventMsg { object_id, event, user_data: None })
+    }
+}
+
+/// Number of messages (and their total size in bytes) of a kind.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl MessageStats {
+    pub fn record(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes as u64;
     }
 }
 
 #[derive(Clone)]
 pub struct WlObjectMetaData {
     object_id: WaylandId,
-    stream: Rc<dyn WaylandStream>,
+    generation: u32,
+    stream: StreamRef,
+    user_data: Option<UserData>,
 }
 
 impl fmt::Debug for WlObjectMetaData {
@@ -126,10 +227,43 @@ impl fmt::Debug for WlObjectMetaData {
     }
 }
 
+/// Fatal error sent by the server through `wl_display.error`, after which the connection is
+/// no longer usable.
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub object_id: WaylandId,
+    /// Interface of the object, empty if the object is unknown to the client.
+    pub interface: &'static str,
+    pub code: u32,
+    /// The code decoded against the error enum of `interface`.
+    pub code_name: Option<&'static str>,
+    pub message: String,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}: error ", self.interface, self.object_id)?;
+        match self.code_name {
+            Some(name) => write!(f, "{name} ({})", self.code)?,
+            None => write!(f, "{}", self.code)?,
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     NoEvent(WlEventId),
+    // the connection was closed because of a protocol error
+    Fatal(ProtocolError),
+    // the connection was closed by the server (e.g. the compositor crashed)
+    Disconnected,
+    // the object was already destroyed by a destructor request
+    DeadObject(WaylandId),
     UnexpectedExtraBytes { object_id : u32, event_id : u16, extra_bytes : usize },
+    // a message shorter than its header or longer than the protocol allows, after which the
+    // messages can't be told apart anymore
+    InvalidMessageSize(usize),
     ParsingError(parsing::Error),
     IoError(IoError),
 }
@@ -150,6 +284,11 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::result::Result<(), fmt::Error> {
         // TODO: implement Display for error::Error so that this can be more beautiful
         // TODO: implment display for this
-        write!(f, "{self:?}")
+        match self {
+            Self::Fatal(error) => write!(f, "Fatal protocol error, {error}"),
+            Self::Disconnected => write!(f, "Disconnected from the compositor"),
+            Self::InvalidMessageSize(size) => write!(f, "Received a message of invalid size {size}"),
+            _ => write!(f, "{self:?}"),
+        }
     }
 }
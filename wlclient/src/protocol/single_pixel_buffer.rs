@@ -0,0 +1,16 @@
+use super::macros::declare_interfaces;
+use super::base::WlBuffer;
+
+declare_interfaces! {
+    @FirstId = 400,
+
+    @interface(WpSinglePixelBufferManagerV1) {
+        @requests {
+            @destructor destroy();
+            // the channels go from 0 to u32::MAX, with a premultiplied alpha
+            create_u32_rgba_buffer(buffer: &WlBuffer, r: u32, g: u32, b: u32, a: u32) => [
+                Uint32(buffer.get_object_id()), Uint32(r), Uint32(g), Uint32(b), Uint32(a),
+            ];
+        }
+    },
+}
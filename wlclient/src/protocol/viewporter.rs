@@ -0,0 +1,29 @@
+use super::macros::declare_interfaces;
+use super::base::WlSurface;
+
+declare_interfaces! {
+    @FirstId = 300,
+
+    @interface(WpViewporter) {
+        @requests {
+            @destructor destroy();
+            get_viewport(viewport: &WpViewport, surface: &WlSurface) => [
+                Uint32(viewport.get_object_id()), Uint32(surface.get_object_id()),
+            ];
+        }
+        @errors { viewport_exists = 0, }
+    },
+
+    @interface(WpViewport) {
+        @requests {
+            @destructor destroy();
+            // the source rectangle is in wl_fixed (24.8 fixed point), -1 everywhere unsets it
+            set_source(x: f64, y: f64, width: f64, height: f64) => [
+                Int32(f64_to_fixed(x)), Int32(f64_to_fixed(y)), Int32(f64_to_fixed(width)), Int32(f64_to_fixed(height)),
+            ];
+            // -1 for both unsets the destination size
+            set_destination(width: i32, height: i32) => [ Int32(width), Int32(height) ];
+        }
+        @errors { bad_value = 0, bad_size = 1, out_of_buffer = 2, no_surface = 3, }
+    },
+}
@@ -1,7 +1,8 @@
 use super::*;
+use crate::sync::Lock;
 use std::{
-    cell::RefCell,
-    io::{IoSlice, Write},
+    collections::{HashMap, HashSet},
+    io::{ErrorKind, IoSlice, Write},
     os::unix::net::{SocketAncillary, UnixStream},
 };
 
@@ -30,14 +31,75 @@ impl WireMsgHeader {
 }
 
 
-pub struct ClientStream(RefCell<UnixStream>);
+pub struct ClientStream {
+    socket: Lock<UnixStream>,
+    // objects for which a destructor request was sent but whose deletion wasn't acknowledged yet
+    dead_objects: Lock<HashSet<WaylandId>>,
+    // number of deletions acknowledged for each id, see `WaylandStream::generation`
+    generations: Lock<HashMap<WaylandId, u32>>,
+    fatal_error: Lock<Option<ProtocolError>>,
+    disconnected: Lock<bool>,
+    sent: Lock<SentStats>,
+}
+
+// requests sent by interface and opcode, plus the file descriptors sent
+type SentStats = (HashMap<(&'static str, u16), MessageStats>, u64);
+
 impl ClientStream {
     pub fn new(stream: UnixStream) -> Self {
-        Self(RefCell::new(stream))
+        Self {
+            socket: Lock::new(stream),
+            dead_objects: Lock::new(HashSet::new()),
+            generations: Lock::new(HashMap::new()),
+            fatal_error: Lock::new(None),
+            disconnected: Lock::new(false),
+            sent: Lock::new(SentStats::default()),
+        }
+    }
+
+    pub fn sent_stats(&self) -> SentStats {
+        self.sent.lock().clone()
+    }
+
+    pub fn reset_sent_stats(&self) {
+        *self.sent.lock() = SentStats::default();
+    }
+
+    /// Makes every following request fail with [`Error::Disconnected`].
+    pub fn set_disconnected(&self) {
+        *self.disconnected.lock() = true;
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        *self.disconnected.lock()
+    }
+
+    /// Makes every following request fail with `error`.
+    pub fn set_fatal_error(&self, error: ProtocolError) {
+        self.fatal_error.lock().get_or_insert(error);
+    }
+
+    pub fn get_fatal_error(&self) -> Option<ProtocolError> {
+        self.fatal_error.lock().clone()
+    }
+
+    pub fn is_dead(&self, object_id: WaylandId) -> bool {
+        self.dead_objects.lock().contains(&object_id)
+    }
+
+    /// Should be called once the server acknowledges the deletion of the object (since its id
+    /// might be reused afterwards). The proxies of the deleted object stay dead.
+    pub fn forget_object(&self, object_id: WaylandId) {
+        *self.generations.lock().entry(object_id).or_default() += 1;
+        self.dead_objects.lock().remove(&object_id);
     }
 }
 
 impl WaylandStream for ClientStream {
+    fn generation(&self, object_id: WaylandId) -> u32 {
+        self.generations.lock().get(&object_id).copied().unwrap_or_default()
+    }
+
     fn send(&self, msg: WireMessage<'_>) -> Result<usize> {
         // 128 bytes seems to be a good default. For what I've seen I think that
         // hardly any request will need so many bytes.
@@ -62,10 +124,7 @@ impl WaylandStream for ClientStream {
                     let str_size = str_size as usize;
                     let aligned_size = str_aligned_size(str_size);
 
-                    // buffer.resize(aligned_size, 0u8)
-                    for _ in 0..(aligned_size - str_size) {
-                        buffer.push(0u8);
-                    }
+                    buffer.resize(buffer.len() + aligned_size - str_size, 0u8);
                 }
                 FileDesc(fd) => {
                     assert!(file_desc.is_none());
@@ -88,7 +147,20 @@ impl WaylandStream for ClientStream {
         let bytes = size_and_event_id.to_ne_bytes();
         buffer[4..8].copy_from_slice(&bytes);
 
-        let size = match file_desc {
+        let mut socket = self.socket.lock();
+        if self.is_disconnected() {
+            return Err(Error::Disconnected);
+        }
+
+        if let Some(error) = self.get_fatal_error() {
+            return Err(Error::Fatal(error));
+        }
+
+        if self.is_dead(msg.object_id) || self.generation(msg.object_id) != msg.generation {
+            return Err(Error::DeadObject(msg.object_id));
+        }
+
+        let result = match file_desc {
             Some(fd) => {
                 // 32 is s total random number, I think I only need 4 but I am not sure
                 // TODO: do research on this ...
@@ -96,17 +168,39 @@ impl WaylandStream for ClientStream {
                 let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
                 ancillary.add_fds(&[fd][..]);
 
-                self.0
-                    .borrow_mut()
-                    .send_vectored_with_ancillary(&[IoSlice::new(&buffer)][..], &mut ancillary)?
+                socket.send_vectored_with_ancillary(&[IoSlice::new(&buffer)][..], &mut ancillary)
             }
-            None => self.0.borrow_mut().write(&buffer)?,
+            None => socket.write(&buffer),
         };
 
+        let size = match result {
+            Err(err) if is_disconnect(&err) => {
+                self.set_disconnected();
+                return Err(Error::Disconnected);
+            }
+            other => other?,
+        };
+
+        if msg.destructor {
+            self.dead_objects.lock().insert(msg.object_id);
+        }
+
+        let mut sent = self.sent.lock();
+        sent.0.entry((msg.interface, msg.request_id as u16)).or_default().record(size);
+        sent.1 += file_desc.is_some() as u64;
+
         Ok(size)
     }
 }
 
+/// Whether `error` means that the other end closed the connection.
+pub fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
 // helper functions
 fn u32_from_bytes(data: &[u8]) -> u32 {
     assert!(data.len() == 4);
@@ -125,13 +219,13 @@ fn write_bytes(writer: &mut impl Write, data: &[u8]) {
 
 #[inline]
 fn str_aligned_size(base_size: usize) -> usize {
-    ((base_size + 4 - 1) / 4) * 4
+    base_size.div_ceil(4) * 4
 }
 
 // parsing helper functions
 pub mod parsing {
     use super::{str_aligned_size, u32_from_bytes};
-    use std::str;
+    use std::{os::fd::OwnedFd, str};
 
 
     //use super::*;
@@ -196,6 +290,25 @@ pub mod parsing {
         Ok(array)
     }
 
+    // arrays of anything else than u32 (e.g. u16 indices), so the size might not be a multiple
+    // of 32 bits but the array is still padded to it
+    pub fn parse_byte_array(iter: &mut impl Iterator<Item = u8>) -> Result<Vec<u8>> {
+        let size = parse_field!(parse_u32(iter), "byte array size")? as usize;
+
+        let array = next_n_bytes(iter, size)
+            .ok_or(custom_err!("Failed to get {size} bytes for the array data."))?;
+
+        let padding = str_aligned_size(size) - size;
+        iter.advance_by(padding)
+            .map_err(|_| custom_err!("Failed to get the {padding} padding bytes!"))?;
+
+        Ok(array)
+    }
+
+    pub fn parse_fd(fds: &mut impl Iterator<Item = OwnedFd>) -> Result<OwnedFd> {
+        fds.next().ok_or(Error::MissingField("file descriptor"))
+    }
+
     pub fn parse_str(iter: &mut impl Iterator<Item = u8>) -> Result<String> {
         let str_size = parse_field!(parse_u32(iter), "Failed to get String size.")? as usize;
 
@@ -221,3 +334,28 @@ pub mod parsing {
         Some(values)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::base::WlBuffer, sync::Shared};
+
+    #[test]
+    fn proxies_of_deleted_objects_stay_dead() {
+        let (socket, _server) = UnixStream::pair().unwrap();
+        let stream = Shared::new(ClientStream::new(socket));
+        let stream_ref: StreamRef = stream.clone();
+
+        let buffer = WlBuffer::build(5, stream_ref.clone(), None);
+        let stale = buffer.clone();
+        assert!(buffer.destroy().is_ok());
+        assert!(matches!(stale.destroy(), Err(Error::DeadObject(5))));
+
+        // the id is reused once the deletion is acknowledged, but not by the old proxies
+        stream.forget_object(5);
+        assert!(matches!(stale.destroy(), Err(Error::DeadObject(5))));
+
+        let reused = WlBuffer::build(5, stream_ref, None);
+        assert!(reused.destroy().is_ok());
+    }
+}
@@ -6,11 +6,11 @@ use super::base::WlSurface;
 declare_interfaces! {
     @FirstId = 100,
     XdgPositioner,
-    XdgPopUp,
+    XdgPopUp as "xdg_popup",
 
-    @interface(XdgSurface) {
+    @interface(XdgSurface, version = 6) {
         @requests {
-            destroy();
+            @destructor destroy();
             get_toplevel(top_level : &XdgTopLevel) => [ Uint32(top_level.get_object_id()) ];
             get_popup(popup : &XdgPopUp, parent : &XdgSurface, positioner : &XdgPositioner) => [ 
                 Uint32(popup.get_object_id()), Uint32(parent.get_object_id()), Uint32(positioner.get_object_id()),
@@ -21,11 +21,15 @@ declare_interfaces! {
             ack_configure(serial: u32) => [ Uint32( serial ) ];
         }
         @events { configure( serial_nr : u32); }
+        @errors {
+            not_constructed = 1, already_constructed = 2, unconfigured_buffer = 3,
+            invalid_serial = 4, invalid_size = 5, defunct_role_object = 6,
+        }
     },
 
-    @interface(XdgTopLevel) {
+    @interface(XdgTopLevel as "xdg_toplevel", version = 6) {
         @requests {
-            destroy();
+            @destructor destroy();
             set_parent(parent : &XdgTopLevel) => [ Uint32(parent.get_object_id()) ];
             set_title(title: &str)   => [ Str(title.to_string()) ];
             set_app_id(app_id: &str) => [ Str(app_id.to_string()) ];
@@ -37,11 +41,13 @@ declare_interfaces! {
             configure_bounds(width: i32, height: i32);
             wm_capabilities(capabilities: Array);
         }
+
+        @errors { invalid_resize_edge = 0, invalid_parent = 1, invalid_size = 2, }
     },
 
-    @interface(XdgWmBase) {
+    @interface(XdgWmBase, version = 6) {
         @requests {
-            destroy();
+            @destructor destroy();
             create_positioner(positioner : &XdgPositioner) => [ Uint32(positioner.get_object_id()) ];
             get_xdg_surface(xdg_surface: &XdgSurface, surface: &WlSurface) => [
                 Uint32(xdg_surface.get_object_id()), Uint32(surface.get_object_id())
@@ -50,6 +56,10 @@ declare_interfaces! {
         }
 
         @events { ping(serial : u32); }
+        @errors {
+            role = 0, defunct_surfaces = 1, not_the_topmost_popup = 2, invalid_popup_parent = 3,
+            invalid_surface_state = 4, invalid_positioner = 5, unresponsive = 6,
+        }
     }
 
 }
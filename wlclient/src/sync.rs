@@ -0,0 +1,77 @@
+//! Sharing primitives used across the crate.
+//!
+//! By default the client is meant to be used from a single thread, so everything is shared
+//! through `Rc` and `RefCell`. With the `thread-safe` feature enabled the very same types are
+//! backed by `Arc` and `Mutex`, making proxies (and the client itself) `Send + Sync`, so that
+//! requests can be sent from worker threads while another thread dispatches the events.
+
+#[cfg(not(feature = "thread-safe"))]
+mod imp {
+    use std::cell::{RefCell, RefMut};
+
+    pub type Shared<T> = std::rc::Rc<T>;
+    pub type LockGuard<'a, T> = RefMut<'a, T>;
+
+    pub struct Lock<T: ?Sized>(RefCell<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Self(RefCell::new(value))
+        }
+    }
+
+    impl<T: ?Sized> Lock<T> {
+        /// Panics if the lock is already being held (i.e. it is not reentrant).
+        pub fn lock(&self) -> LockGuard<'_, T> {
+            self.0.borrow_mut()
+        }
+    }
+
+    /// Marker trait that is equivalent to [`Send`] when the `thread-safe` feature is enabled.
+    pub trait MaybeSend {}
+    impl<T: ?Sized> MaybeSend for T {}
+
+    /// Marker trait that is equivalent to [`Sync`] when the `thread-safe` feature is enabled.
+    pub trait MaybeSync {}
+    impl<T: ?Sized> MaybeSync for T {}
+}
+
+#[cfg(feature = "thread-safe")]
+mod imp {
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    pub type Shared<T> = std::sync::Arc<T>;
+    pub type LockGuard<'a, T> = MutexGuard<'a, T>;
+
+    pub struct Lock<T: ?Sized>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Self(Mutex::new(value))
+        }
+    }
+
+    impl<T: ?Sized> Lock<T> {
+        /// Deadlocks if the lock is already being held by the current thread.
+        pub fn lock(&self) -> LockGuard<'_, T> {
+            // a panicking handler shouldn't make the whole client unusable
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    pub trait MaybeSend: Send {}
+    impl<T: ?Sized + Send> MaybeSend for T {}
+
+    pub trait MaybeSync: Sync {}
+    impl<T: ?Sized + Sync> MaybeSync for T {}
+
+    // compile time check that the thread-safe variant is really thread-safe
+    const _: fn() = || {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<crate::WaylandClient<()>>();
+        assert_send_sync::<crate::client::ClientHandle<()>>();
+        assert_send_sync::<crate::protocol::base::WlSurface>();
+    };
+}
+
+pub use imp::*;